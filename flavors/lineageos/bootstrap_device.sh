@@ -0,0 +1,57 @@
+#!/usr/bin/env bash
+# SPDX-FileCopyrightText: 2026 robotnix contributors
+# SPDX-License-Identifier: MIT
+
+# Fetch everything needed to build a single device, and print a configuration to start from
+
+set -euo pipefail
+
+cd "$(dirname "${BASH_SOURCE[0]}")"
+
+if [[ $# -ne 1 ]]; then
+    echo "Usage: $0 <device>" >&2
+    exit 1
+fi
+device="$1"
+
+export TMPDIR=/var/tmp
+# Reuse ls-remote results between the updaters, but only within this run (see update.sh)
+if [[ -z "${ROBOTNIX_LS_REMOTE_CACHE_TTL:-}" ]]; then
+    run_cache_dir="$(mktemp -d "${TMPDIR}/robotnix-run-cache-XXXXXX")"
+    trap 'rm -rf "$run_cache_dir"' EXIT
+    export ROBOTNIX_LS_REMOTE_CACHE_FILE="${run_cache_dir}/ls-remote.json"
+    export ROBOTNIX_LS_REMOTE_CACHE_TTL=86400
+fi
+export ROBOTNIX_CHECKOUT_CACHE="${ROBOTNIX_CHECKOUT_CACHE:-1}"
+
+# --allow-unlisted, since a device being bootstrapped usually isn't in supported_devices.toml yet
+./update_device_metadata.py --device "$device" --allow-unlisted
+branch="$(python3 -c 'import json, sys; print(json.load(sys.stdin).get(sys.argv[1], {}).get("branch", ""))' \
+    "$device" < device-metadata.json)"
+if [[ -z "$branch" ]]; then
+    echo "$device is not a supported LineageOS device" >&2
+    exit 1
+fi
+
+if [[ ! -f "${branch}/repo.json" ]]; then
+    ../../scripts/mk_repo_file.py --out "${branch}/repo.json" --cache-search-path ../../ --ref-type branch \
+        "https://github.com/LineageOS/android" "$branch"
+fi
+./update_device_dirs.py --branch "$branch" --device "$device"
+
+# The androidVersion of the branch, see androidVersionToLineageBranch in default.nix
+major="${branch#lineage-}"
+android_version=$(( ${major%%.*} - 7 ))
+
+cat <<EOF
+
+$device is on $branch. A configuration to start from:
+
+{
+  device = "$device";
+  flavor = "lineageos";
+  # $branch, the newest branch of $device in device-metadata.json. Its sources are pinned in
+  # ${branch}/repo.json, ${branch}/device-dirs.json and ${branch}/vendor-dirs.json
+  androidVersion = $android_version;
+}
+EOF
@@ -0,0 +1,194 @@
+#!/usr/bin/env python3
+# SPDX-FileCopyrightText: 2026 robotnix contributors
+# SPDX-License-Identifier: MIT
+
+from typing import Any, Dict, List, Optional, Set, Tuple, cast
+
+import argparse
+import json
+import os
+import pathlib
+import re
+import sys
+import tomllib
+
+from robotnix_common import normalize_url
+from update_device_dirs import relpath_with_deps, get_required_vendor_dirs
+
+
+# Load repo.json, device-dirs.json and vendor-dirs.json for every branch directory
+def load_branches() -> Dict[str, Dict[str, Any]]:
+    branches: Dict[str, Dict[str, Any]] = {}
+    for branch in sorted(os.listdir('.')):
+        if not (branch.startswith('lineage-') and os.path.isdir(branch)):
+            continue
+        branches[branch] = {}
+        for name in ['repo', 'device-dirs', 'vendor-dirs']:
+            filename = os.path.join(branch, f'{name}.json')
+            branches[branch][name] = json.load(open(filename)) if os.path.exists(filename) else {}
+    return branches
+
+
+# Returns errors (things a build would trip over) and warnings (stale entries nothing refers to)
+def check_consistency(metadata: Dict[str, Any], branches: Dict[str, Dict[str, Any]]) -> Tuple[List[str], List[str]]:
+    errors: List[str] = []
+    warnings: List[str] = []
+
+    for device, data in sorted(metadata.items()):
+        branch = data.get('branch')
+        if branch not in branches:
+            errors.append(f'{device} defaults to {branch}, which has no pinned sources')
+            continue
+        relpath = f"device/{data['vendor']}/{device}"
+        if relpath not in branches[branch]['device-dirs']:
+            errors.append(f'{device}: {relpath} is missing from {branch}/device-dirs.json')
+
+    for branch, files in branches.items():
+        device_dirs = files['device-dirs']
+        for relpath, dir_info in sorted(device_dirs.items()):
+            for dep in dir_info.get('deps', []):
+                if dep not in device_dirs and dep not in files['repo']:
+                    errors.append(f'{branch}: {relpath} depends on {dep}, which is not pinned')
+
+        # device-dirs.json covers every device which was ever updated on a branch, not just those in
+        # device-metadata.json, so only dirs no device tree at all depends on are stale
+        used: Set[str] = set()
+        for relpath in device_dirs.keys():
+            if relpath.startswith('device/'):
+                used |= relpath_with_deps(device_dirs, relpath)
+        for relpath in sorted(device_dirs.keys() - used):
+            warnings.append(f'{branch}: {relpath} in device-dirs.json is not used by any device tree')
+
+    return errors, warnings
+
+
+# Git branches of LineageOS, like lineage-21 or lineage-22.1
+LINEAGE_BRANCH_RE = re.compile(r'refs/heads/lineage-(\d+)(?:\.\d+)?')
+
+
+# The same relpath normally comes from the same repo on every branch. A fork on one branch and
+# upstream on another is usually a typo in some lineage.dependencies, so flag those. Likewise for
+# the branches which lineage.dependencies asks for (recorded as 'ref'), which should still look like
+# a LineageOS branch. Tags and commits are deliberate pins, so they are left alone.
+def check_cross_branch(branches: Dict[str, Dict[str, Any]]) -> List[str]:
+    warnings: List[str] = []
+    urls: Dict[str, Dict[str, List[str]]] = {}  # relpath: { normalized url: [ branch ] }
+    for branch, files in branches.items():
+        for relpath, dir_info in sorted(files['device-dirs'].items()):
+            url = normalize_url(dir_info['url'])
+            urls.setdefault(relpath, {}).setdefault(url, []).append(branch)
+            ref = dir_info.get('ref', '')
+            if ref.startswith('refs/heads/') and not LINEAGE_BRANCH_RE.fullmatch(ref):
+                warnings.append(f"{branch}: {relpath} is pinned to {ref}, which doesn't look like a LineageOS branch")
+
+    for relpath, branches_by_url in sorted(urls.items()):
+        if len(branches_by_url) > 1:
+            sources = '; '.join(f"{url} on {', '.join(b)}" for url, b in sorted(branches_by_url.items()))
+            warnings.append(f'{relpath} comes from different repos across branches: {sources}')
+    return warnings
+
+
+# Android version of each LineageOS version, as in androidVersionToLineageBranch in default.nix
+ANDROID_VERSIONS = {'17': 10, '18': 11, '19': 12, '20': 13, '21': 14, '22': 15}
+
+
+# The LineageOS version (like 21) of a branch name (lineage-21.0) or git branch (refs/heads/lineage-21)
+def lineage_version(branch: str) -> Optional[str]:
+    match = LINEAGE_BRANCH_RE.fullmatch(branch if branch.startswith('refs/') else f'refs/heads/{branch}')
+    return match.group(1) if match is not None else None
+
+
+# Devices have to be built from dirs of their own LineageOS version. A dependency pinned to the branch
+# of another version (like lineage-20 for a device on lineage-22.1) builds against the wrong Android
+# version, which usually means its lineage.dependencies wasn't updated when the device was brought up.
+def check_versions(metadata: Dict[str, Any], branches: Dict[str, Dict[str, Any]]) -> List[str]:
+    warnings: List[str] = []
+    for device, data in sorted(metadata.items()):
+        branch = data['branch']
+        version = lineage_version(branch)
+        if version not in ANDROID_VERSIONS:
+            warnings.append(f'{device} is on {branch}, which default.nix has no androidVersion for')
+            continue
+        if branch not in branches:
+            continue
+        device_dirs = branches[branch]['device-dirs']
+        relpath = f"device/{data['vendor']}/{device}"
+        for dep in sorted(relpath_with_deps(device_dirs, relpath) if relpath in device_dirs else set()):
+            ref = device_dirs.get(dep, {}).get('ref', '')
+            dep_version = lineage_version(ref) if ref.startswith('refs/heads/') else None
+            if dep_version is not None and dep_version != version:
+                dep_android = ANDROID_VERSIONS.get(dep_version, 'unknown')
+                warnings.append(f'{device} is on {branch} (Android {ANDROID_VERSIONS[version]}), but {dep} is '
+                                f'pinned to {ref} (Android {dep_android}). Fix the lineage.dependencies asking for it')
+    return warnings
+
+
+# Check what a device needs to be buildable from the pinned sources of its default branch
+def device_completeness(device: str, data: Any, files: Dict[str, Any]) -> Dict[str, bool]:
+    device_dirs = files['device-dirs']
+    relpath = f"device/{data['vendor']}/{device}"
+    relpaths = relpath_with_deps(device_dirs, relpath) if relpath in device_dirs else set()
+    required_vendor = get_required_vendor_dirs({device: data}, data['branch'], device_dirs)[device]
+    vendor_relpaths = [f'vendor/{p}' for p in required_vendor]
+
+    return {
+        'device tree': relpath in device_dirs,
+        'dependencies': all(p in device_dirs or p in files['repo'] for p in relpaths),
+        'kernel source': any(p.startswith('kernel/') for p in relpaths),
+        'vendor blobs': len(vendor_relpaths) > 0 and all(p in files['vendor-dirs'] for p in vendor_relpaths),
+    }
+
+
+def print_completeness_report(metadata: Dict[str, Any], branches: Dict[str, Dict[str, Any]]) -> None:
+    for device, data in sorted(metadata.items()):
+        if data['branch'] not in branches:
+            print(f"{device} ({data['branch']}): 0/4, no pinned sources")
+            continue
+        checks = device_completeness(device, data, branches[data['branch']])
+        missing = [name for name, ok in checks.items() if not ok]
+        line = f"{device} ({data['branch']}): {len(checks) - len(missing)}/{len(checks)}"
+        if len(missing) > 0:
+            line += f", missing {', '.join(missing)}"
+        print(line)
+
+
+# Errors which are known and accepted, like those in the pinned sources of end-of-life branches.
+# These don't fail the check, so it can gate changes which would introduce new ones
+def load_baseline(filename: str) -> List[str]:
+    return cast(List[str], tomllib.loads(open(filename).read()).get('known_errors', []))
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser()
+    parser.add_argument('--report', action='store_true',
+                        help="also print how complete the pinned sources of each device are")
+    parser.add_argument('--baseline', default='consistency_baseline.toml',
+                        help="TOML file with a known_errors list of errors which don't fail the check")
+    args = parser.parse_args()
+
+    metadata = json.load(open('device-metadata.json'))
+    branches = load_branches()
+    if args.report:
+        print_completeness_report(metadata, branches)
+
+    errors, warnings = check_consistency(metadata, branches)
+    warnings += check_cross_branch(branches)
+    warnings += check_versions(metadata, branches)
+    baseline = load_baseline(args.baseline) if os.path.exists(args.baseline) else []
+    for error in sorted(set(baseline) - set(errors)):
+        warnings.append(f'no longer happens, remove it from {args.baseline}: {error}')
+    known = [error for error in errors if error in baseline]
+    errors = [error for error in errors if error not in baseline]
+    if len(known) > 0:
+        print(f'{len(known)} known errors listed in {args.baseline}', file=sys.stderr)
+    for warning in warnings:
+        print(f'WARNING: {warning}', file=sys.stderr)
+    for error in errors:
+        print(f'ERROR: {error}', file=sys.stderr)
+    if len(errors) > 0:
+        sys.exit(1)
+
+
+if __name__ == '__main__':
+    os.chdir(pathlib.Path(__file__).parent.resolve())
+    main()
@@ -9,7 +9,7 @@ let
     mapAttrs mapAttrs' nameValuePair filterAttrs
     attrNames getAttrs flatten remove
     mkIf mkMerge mkDefault mkForce
-    importJSON toLower hasPrefix removePrefix hasSuffix replaceStrings;
+    importJSON hasPrefix removePrefix hasSuffix replaceStrings;
 
   androidVersionToLineageBranch = {
     "10" = "lineage-17.1";
@@ -132,7 +132,7 @@ in mkIf (config.flavor == "lineageos")
     }
   ] ++ optionals (deviceMetadata ? "${config.device}") (let
     # Device-specific source dirs
-    vendor = toLower deviceMetadata.${config.device}.vendor;
+    inherit (deviceMetadata.${config.device}) vendor;
     deviceRelpath = "device/${vendor}/${config.device}";
 
     # Retuns a list of all relpaths for the device (including deps) recursively
@@ -0,0 +1,532 @@
+# SPDX-FileCopyrightText: 2026 robotnix contributors
+# SPDX-License-Identifier: MIT
+
+import json
+import os
+import subprocess
+
+from unittest.mock import patch
+import pytest
+
+from typing import Any, Callable, Dict, List, Optional, cast
+
+import mk_repo_file
+import update_device_dirs
+import update_device_metadata
+from robotnix_common import OfflineError
+from test_update_device_metadata import RECORDED_METADATA, run_update_device_metadata
+
+# Recorded from a real lineage-21.0 run: nix-prefetch-git output for each repo, along with the
+# lineage.dependencies file (if any) found in its checkout.
+RECORDED_REPOS: Dict[str, Dict[str, Any]] = {
+    'https://github.com/LineageOS/android_device_oneplus_cheeseburger': {
+        'date': '2024-09-06T08:38:09+02:00',
+        'hash': 'sha256-4AtVCyXufHnfRCCy05EaR1xxk5/YwSzSkhXPUs06XsY=',
+        'rev': '980b9e58db9998511efce378e10839c9d86ec3fc',
+        'sha256': '1ijy7b6m5kqmjb92rhfqky9p2p273a8x7ci08kgpjz7f4l5ma2z0',
+        'lineage.dependencies': [
+            {'repository': 'android_device_oneplus_msm8998-common', 'target_path': 'device/oneplus/msm8998-common'},
+        ],
+    },
+    'https://github.com/LineageOS/android_device_oneplus_msm8998-common': {
+        'date': '2024-09-06T06:31:08+02:00',
+        'hash': 'sha256-G9oA1OWcQnErkFAjUV/GyJlFjPhn5Pnr9V2N4qia46s=',
+        'rev': '05778eb6d16caa16c6ed67cf4ae12d29bdc0330f',
+        'sha256': '1az3kalf53axypmzkr37z264b6f8qrgm28shj0mp2hlwwpa01nhv',
+        'lineage.dependencies': [
+            {'repository': 'android_hardware_oneplus', 'target_path': 'hardware/oneplus'},
+            {'repository': 'android_kernel_oneplus_msm8998', 'target_path': 'kernel/oneplus/msm8998'},
+        ],
+    },
+    'https://github.com/LineageOS/android_hardware_oneplus': {
+        'date': '2024-10-16T21:27:36+00:00',
+        'hash': 'sha256-reV7voGjDhgBGQlecCjQ4XNAq0VGaSGGgTkziPQYNjw=',
+        'rev': '97a07a100dd3b2fcdc96b5098ec2f8d1f3358f73',
+        'sha256': '0g1n33s8hcrrh6322sa68nml0wz1s0l70ph9340ih3m3h6z7prdd',
+    },
+    'https://github.com/LineageOS/android_kernel_oneplus_msm8998': {
+        'date': '2024-08-15T22:11:56+02:00',
+        'hash': 'sha256-UlIIsQyZvElouInBamAQX16lPNfqkZ/FMvXcl5SS2B0=',
+        'rev': '90c91e92c4e8d1a743321554f1aa9aac8c79a3df',
+        'sha256': '07fqjaa9gp7m6b2rz4gaswyaapjz21h6mhc9p1l4kg4r1jqhhljj',
+    },
+    'https://github.com/LineageOS/android_device_fairphone_FP4': {
+        'date': '2024-11-30T17:50:05+02:00',
+        'hash': 'sha256-yfegJbjP+Zx8Mxch4CIwv/quGQNkxNa9Aw1TDfH9mAY=',
+        'rev': '1886b28c5b21a200135ed12c6f600c464eeaf501',
+        'sha256': '01lqzpqhslqd0fyxdi340ccsxymz60if088p6dy9ryfgp0js1xy9',
+        'lineage.dependencies': [
+            {'repository': 'android_kernel_fairphone_sm7225', 'target_path': 'kernel/fairphone/sm7225'},
+        ],
+    },
+    'https://github.com/LineageOS/android_kernel_fairphone_sm7225': {
+        'date': '2024-12-20T03:32:20+02:00',
+        'hash': 'sha256-EC+uLbBzpMb13EY/LxdeOVjnkaY5MlxLSG0d6841jkw=',
+        'rev': '49e6905b374b93018c40496181ca16d1efc5f927',
+        'sha256': '0k4f6p7fn7bd915mqcirls8yfn1rbqbjygs6vkswd93kn0nswbqh',
+    },
+    'https://github.com/TheMuppets/proprietary_vendor_oneplus_cheeseburger': {
+        'date': '2024-02-01T15:00:29+02:00',
+        'hash': 'sha256-AsgXChTiunisxFsgyrh0Y5FcMuSpTQ8dKWFatUVFEKs=',
+        'rev': '2b5b41b090de2cc83a379d32a27a9c6424a1f802',
+        'sha256': '1aqh8m2vank154fhykd9whr5r4b3fjwcl82vqjn7ifp22h51gj02',
+    },
+    'https://github.com/TheMuppets/proprietary_vendor_oneplus_msm8998-common': {
+        'date': '2024-09-08T20:55:04+02:00',
+        'hash': 'sha256-PMgZmHpPW6V4OTpg6VBAQDiOKjD8FSnO1o7m2gduNJk=',
+        'rev': '1cd9f3694568f82142d2e3bbc0498745c8ab3fef',
+        'sha256': '169ldq3xmrlfsv72j5gw60m8wf20818fjq1s75waansggac1kj1w',
+    },
+    'https://github.com/TheMuppets/proprietary_vendor_fairphone_FP4': {
+        'date': '2024-11-30T17:54:54+02:00',
+        'hash': 'sha256-YRfmUJnzbSrFipn9YV/TnEh+FEH8hoxxvrYd80TTMWU=',
+        'rev': '3e298c3fb0e5bf6f1a1e6eeef33aaa1b2cd4a485',
+        'sha256': '0r9isd2g67dnprqqr1pw84a7wj4wsdgn3zcrib2jlvgkk58fc5v1',
+    },
+}
+
+# An excerpt of `repo dumpjson` for the lineage-21.0 manifest, and the nix-prefetch-git output of its projects
+RECORDED_MANIFEST: Dict[str, Dict[str, Any]] = {
+    'build/make': {
+        'groups': ['pdk', 'sysui-studio'],
+        'revisionExpr': 'refs/heads/lineage-21.0',
+        'url': 'https://github.com/LineageOS/android_build',
+    },
+    'vendor/lineage': {
+        'groups': [],
+        'revisionExpr': 'refs/heads/lineage-21.0',
+        'url': 'https://github.com/LineageOS/android_vendor_lineage',
+    },
+}
+RECORDED_MANIFEST_REPOS: Dict[str, Dict[str, Any]] = {
+    'https://github.com/LineageOS/android_build': {
+        'date': '2024-09-04T02:29:49+00:00',
+        'rev': '9531801b02c499820c129fb0ef501b1c76c34550',
+        'sha256': '11c1b9cbx85aa2bjsyi0wqhyzxq45ldnbpbl7smcrza2s7a0y6f6',
+    },
+    'https://github.com/LineageOS/android_vendor_lineage': {
+        'date': '2024-12-26T13:38:33+00:00',
+        'rev': 'b6616cc3eb93306296a6228c9b13d602580ea49b',
+        'sha256': '0jy9wg08fiyx8i15m26nay6ii46c5biyiysnwym6z6kf2fxx69pc',
+    },
+}
+
+DEVICE_METADATA = {
+    'FP4': {'branch': 'lineage-21.0', 'vendor': 'fairphone'},
+    'cheeseburger': {'branch': 'lineage-21.0', 'vendor': 'oneplus'},
+}
+# The proprietary vendor dirs update_device_dirs.py records as nonfree for them
+NONFREE = {
+    'FP4': ['vendor/fairphone/FP4'],
+    'cheeseburger': ['vendor/oneplus/cheeseburger', 'vendor/oneplus/msm8998-common'],
+}
+
+
+def with_nonfree(metadata: Dict[str, Any]) -> Dict[str, Any]:
+    return {device: {**data, 'nonfree': NONFREE[device]} for device, data in metadata.items()}
+
+
+def recorded_checkout(tmpdir: Any, url: str) -> Dict[str, Any]:
+    recorded = RECORDED_REPOS[url]
+    path = tmpdir / url.rsplit('/', 1)[1]
+    if not path.exists():
+        path.mkdir()
+        if 'lineage.dependencies' in recorded:
+            (path / 'lineage.dependencies').write(json.dumps(recorded['lineage.dependencies']))
+    return {
+        'url': url,
+        'rev': recorded['rev'],
+        'date': recorded['date'],
+        'path': str(path),
+        'sha256': recorded['sha256'],
+        'hash': recorded['hash'],
+        'fetchLFS': True,
+        'fetchSubmodules': False,
+        'deepClone': False,
+        'leaveDotGit': False,
+    }
+
+
+def recorded_ls_remote(url: str) -> Dict[str, str]:
+    if url not in RECORDED_REPOS:
+        raise subprocess.CalledProcessError(128, ['git', 'ls-remote', url], stderr=b'remote: Repository not found.')
+    # LineageOS 21 branches are called lineage-21, not lineage-21.0
+    return {'refs/heads/lineage-21': RECORDED_REPOS[url]['rev']}
+
+
+@pytest.fixture
+def lineageos_dir(tmpdir: Any) -> Any:
+    """A LineageOS flavor dir with DEVICE_METADATA and an empty lineage-21.0, made the current dir"""
+    workdir = tmpdir.mkdir('lineageos')
+    workdir.mkdir('lineage-21.0')
+    os.chdir(workdir)
+    write_device_metadata(DEVICE_METADATA)
+    return workdir
+
+
+def write_device_metadata(metadata: Dict[str, Any]) -> None:
+    with open('device-metadata.json', 'w') as f:
+        json.dump(metadata, f)
+
+
+def run_update_device_dirs(tmpdir: Any, *args: str,
+                           ls_remote: Callable[[str], Dict[str, str]] = recorded_ls_remote,
+                           checkout_git: Optional[Callable[[str, str], Dict[str, Any]]] = None,
+                           github_api: Optional[Dict[str, Any]] = None) -> None:
+    """Run update_device_dirs.py for lineage-21.0 against the recorded repos, or the given fakes"""
+    def checkout_recorded(url: str, rev: str) -> Dict[str, Any]:
+        return recorded_checkout(tmpdir, url)
+
+    with patch('update_device_dirs.ls_remote', side_effect=ls_remote), \
+            patch('update_device_dirs.checkout_git', side_effect=checkout_git or checkout_recorded), \
+            patch('update_device_dirs.github_api', return_value=github_api,
+                  side_effect=None if github_api is not None else Exception('Searched GitHub for a moved repo')), \
+            patch('update_device_dirs.check_free_space'), \
+            patch('sys.argv', ['update_device_dirs.py', '--branch', 'lineage-21.0', *args]):
+        update_device_dirs.main()
+
+
+def run_mk_repo_file(tmpdir: Any) -> None:
+    """Run mk_repo_file.py for lineage-21.0 like update.sh does, against the recorded manifest"""
+    def check_output(args: List[str], cwd: str) -> bytes:
+        assert args == ['repo', 'dumpjson']
+        return json.dumps(RECORDED_MANIFEST).encode()
+
+    def resolve_ref(url: str, ref: str) -> str:
+        assert ref == 'refs/heads/lineage-21.0'
+        return cast(str, RECORDED_MANIFEST_REPOS[url]['rev'])
+
+    def checkout_git(url: str, rev: str, fetch_submodules: bool, fetch_lfs: bool) -> Dict[str, Any]:
+        return {**RECORDED_MANIFEST_REPOS[url], 'url': url, 'path': str(tmpdir)}
+
+    with patch('mk_repo_file.subprocess.check_call'), \
+            patch('mk_repo_file.subprocess.check_output', side_effect=check_output), \
+            patch('mk_repo_file.resolve_ref', side_effect=resolve_ref), \
+            patch('mk_repo_file.checkout_git', side_effect=checkout_git), \
+            patch('mk_repo_file.check_free_space'), \
+            patch.dict(mk_repo_file.revInfo, clear=True), patch.dict(mk_repo_file.treeInfo, clear=True), \
+            patch('sys.argv', ['mk_repo_file.py', '--out', 'lineage-21.0/repo.json', '--ref-type', 'branch',
+                               'https://github.com/LineageOS/android', 'lineage-21.0']):
+        mk_repo_file.main()
+
+
+def test_end_to_end(tmpdir: Any, lineageos_dir: Any) -> None:
+    # The updaters in the order update.sh runs them
+    os.remove('device-metadata.json')
+    run_update_device_metadata(tmpdir)
+    assert json.load(open('device-metadata.json')) == RECORDED_METADATA
+
+    run_mk_repo_file(tmpdir)
+    assert json.load(open('lineage-21.0/repo.json')) == {
+        'build/make': {
+            **RECORDED_MANIFEST['build/make'],
+            'rev': '9531801b02c499820c129fb0ef501b1c76c34550',
+            'dateTime': 1725416989,
+            'sha256': '11c1b9cbx85aa2bjsyi0wqhyzxq45ldnbpbl7smcrza2s7a0y6f6',
+        },
+        'vendor/lineage': {
+            **RECORDED_MANIFEST['vendor/lineage'],
+            'rev': 'b6616cc3eb93306296a6228c9b13d602580ea49b',
+            'dateTime': 1735220313,
+            'sha256': '0jy9wg08fiyx8i15m26nay6ii46c5biyiysnwym6z6kf2fxx69pc',
+        },
+    }
+
+    checked_out: List[str] = []
+
+    def checkout_git(url: str, rev: str) -> Dict[str, Any]:
+        assert rev == 'refs/heads/lineage-21'
+        checked_out.append(url)
+        return recorded_checkout(tmpdir, url)
+
+    run_update_device_dirs(tmpdir, '--jobs', '2', checkout_git=checkout_git)
+
+    def expected(urls_by_relpath: Dict[str, str]) -> Dict[str, Any]:
+        result = {}
+        for relpath, url in urls_by_relpath.items():
+            result[relpath] = recorded_checkout(tmpdir, url)
+        return result
+
+    expected_device_dirs = expected({
+        'device/fairphone/FP4': 'https://github.com/LineageOS/android_device_fairphone_FP4',
+        'device/oneplus/cheeseburger': 'https://github.com/LineageOS/android_device_oneplus_cheeseburger',
+        'device/oneplus/msm8998-common': 'https://github.com/LineageOS/android_device_oneplus_msm8998-common',
+        'hardware/oneplus': 'https://github.com/LineageOS/android_hardware_oneplus',
+        'kernel/fairphone/sm7225': 'https://github.com/LineageOS/android_kernel_fairphone_sm7225',
+        'kernel/oneplus/msm8998': 'https://github.com/LineageOS/android_kernel_oneplus_msm8998',
+    })
+    for relpath, dir_info in expected_device_dirs.items():
+        dependencies = RECORDED_REPOS[dir_info['url']].get('lineage.dependencies', [])
+        dir_info['deps'] = [dep['target_path'] for dep in dependencies]
+    assert json.load(open('lineage-21.0/device-dirs.json')) == expected_device_dirs
+
+    assert json.load(open('lineage-21.0/vendor-dirs.json')) == expected({
+        'vendor/fairphone/FP4': 'https://github.com/TheMuppets/proprietary_vendor_fairphone_FP4',
+        'vendor/oneplus/cheeseburger': 'https://github.com/TheMuppets/proprietary_vendor_oneplus_cheeseburger',
+        'vendor/oneplus/msm8998-common': 'https://github.com/TheMuppets/proprietary_vendor_oneplus_msm8998-common',
+    })
+    assert json.load(open('device-metadata.json')) == with_nonfree(RECORDED_METADATA)
+    assert sorted(checked_out) == sorted(RECORDED_REPOS.keys())
+
+    # A second run against up to date files doesn't check anything out again
+    checked_out.clear()
+    device_dirs = json.load(open('lineage-21.0/device-dirs.json'))
+    run_update_device_dirs(tmpdir, '--frozen', checkout_git=checkout_git)
+    assert checked_out == []
+    assert json.load(open('lineage-21.0/device-dirs.json')) == device_dirs
+
+
+def test_device_filter(tmpdir: Any, lineageos_dir: Any) -> None:
+    run_update_device_dirs(tmpdir, '--device', 'FP4')
+
+    assert sorted(json.load(open('lineage-21.0/device-dirs.json')).keys()) == [
+        'device/fairphone/FP4', 'kernel/fairphone/sm7225']
+    assert sorted(json.load(open('lineage-21.0/vendor-dirs.json')).keys()) == ['vendor/fairphone/FP4']
+    # Devices which weren't selected are left in device-metadata.json
+    assert json.load(open('device-metadata.json')) == {
+        **DEVICE_METADATA,
+        **with_nonfree({'FP4': DEVICE_METADATA['FP4']}),
+    }
+
+
+def test_override(tmpdir: Any, lineageos_dir: Any) -> None:
+    write_device_metadata({'FP4': DEVICE_METADATA['FP4']})
+
+    kernel_url = 'https://github.com/LineageOS/android_kernel_fairphone_sm7225'
+    test_rev = '0123456789abcdef0123456789abcdef01234567'
+
+    def ls_remote(url: str) -> Dict[str, str]:
+        refs = recorded_ls_remote(url)
+        if url == kernel_url:
+            refs['refs/heads/test-kernel'] = test_rev
+        return refs
+
+    def checkout_git(url: str, rev: str) -> Dict[str, Any]:
+        result = recorded_checkout(tmpdir, url)
+        if rev == 'refs/heads/test-kernel':
+            result['rev'] = test_rev
+        return result
+
+    def run(*args: str) -> None:
+        run_update_device_dirs(tmpdir, *args, ls_remote=ls_remote, checkout_git=checkout_git)
+
+    run('--override', 'kernel/fairphone/sm7225=test-kernel')
+    device_dirs = json.load(open('lineage-21.0/device-dirs.json'))
+    assert device_dirs['kernel/fairphone/sm7225']['rev'] == test_rev
+    assert device_dirs['kernel/fairphone/sm7225']['overrideRef'] == 'refs/heads/test-kernel'
+    assert 'overrideRef' not in device_dirs['device/fairphone/FP4']
+
+    # A ref is required
+    with pytest.raises(SystemExit):
+        run('--override', 'kernel/fairphone/sm7225')
+
+    # Overrides are temporary, so --frozen fails as long as one is recorded
+    with pytest.raises(SystemExit):
+        run('--frozen', '--override', 'kernel/fairphone/sm7225=refs/heads/test-kernel')
+
+    # And the next regular run goes back to the branch
+    run()
+    device_dirs = json.load(open('lineage-21.0/device-dirs.json'))
+    assert device_dirs['kernel/fairphone/sm7225']['rev'] == RECORDED_REPOS[kernel_url]['rev']
+    assert 'overrideRef' not in device_dirs['kernel/fairphone/sm7225']
+
+
+def test_reuse_pins_from_other_branches(tmpdir: Any, lineageos_dir: Any) -> None:
+    write_device_metadata({'FP4': DEVICE_METADATA['FP4']})
+
+    # The kernel is at the same revision on lineage-22.1
+    kernel_url = 'https://github.com/LineageOS/android_kernel_fairphone_sm7225'
+    lineageos_dir.mkdir('lineage-22.1')
+    with open('lineage-22.1/device-dirs.json', 'w') as f:
+        json.dump({'kernel/fairphone/sm7225': {**recorded_checkout(tmpdir, kernel_url), 'deps': []}}, f)
+
+    checked_out: List[str] = []
+
+    def checkout_git(url: str, rev: str) -> Dict[str, Any]:
+        checked_out.append(url)
+        return recorded_checkout(tmpdir, url)
+
+    run_update_device_dirs(tmpdir, checkout_git=checkout_git)
+
+    assert kernel_url not in checked_out
+    assert 'https://github.com/LineageOS/android_device_fairphone_FP4' in checked_out
+    device_dirs = json.load(open('lineage-21.0/device-dirs.json'))
+    assert device_dirs['kernel/fairphone/sm7225'] == {**recorded_checkout(tmpdir, kernel_url), 'deps': []}
+
+
+def test_dependency_refs(tmpdir: Any, lineageos_dir: Any) -> None:
+    write_device_metadata({'FP4': DEVICE_METADATA['FP4']})
+
+    device_url = 'https://github.com/LineageOS/android_device_fairphone_FP4'
+    kernel_url = 'https://github.com/LineageOS/android_kernel_fairphone_sm7225'
+    tag_object = '1111111111111111111111111111111111111111'
+    tagged_rev = '2222222222222222222222222222222222222222'
+
+    # Every run moves the device repo to a new revision, whose lineage.dependencies asks for kernel_branch.
+    # Otherwise the device would be up to date, and its lineage.dependencies not read again.
+    def run(kernel_branch: str, device_rev: str) -> Dict[str, Any]:
+        device_path = tmpdir.mkdir(device_rev)
+        (device_path / 'lineage.dependencies').write(json.dumps([{
+            'repository': 'android_kernel_fairphone_sm7225',
+            'target_path': 'kernel/fairphone/sm7225',
+            'branch': kernel_branch,
+        }]))
+
+        def ls_remote(url: str) -> Dict[str, str]:
+            refs = recorded_ls_remote(url)
+            if url == device_url:
+                refs['refs/heads/lineage-21'] = device_rev
+            elif url == kernel_url:
+                refs['refs/tags/v1'] = tag_object
+                refs['refs/tags/v1^{}'] = tagged_rev
+            return refs
+
+        def checkout_git(url: str, rev: str) -> Dict[str, Any]:
+            result = recorded_checkout(tmpdir, url)
+            if url == device_url:
+                result['rev'] = device_rev
+                result['path'] = str(device_path)
+            elif rev != 'refs/heads/lineage-21':
+                result['rev'] = rev if rev != 'refs/tags/v1' else tagged_rev
+            return result
+
+        run_update_device_dirs(tmpdir, ls_remote=ls_remote, checkout_git=checkout_git)
+        return cast(Dict[str, Any], json.load(open('lineage-21.0/device-dirs.json'))['kernel/fairphone/sm7225'])
+
+    # Annotated tags are peeled to the commit they point to
+    kernel = run('refs/tags/v1', '4444444444444444444444444444444444444444')
+    assert kernel['rev'] == tagged_rev
+    assert kernel['ref'] == 'refs/tags/v1'
+
+    commit = '3333333333333333333333333333333333333333'
+    kernel = run(commit, '5555555555555555555555555555555555555555')
+    assert kernel['rev'] == commit
+    assert kernel['ref'] == commit
+
+    # Naming the branch we're updating anyway is the same as not naming one
+    kernel = run('lineage-21', '6666666666666666666666666666666666666666')
+    assert kernel['rev'] == RECORDED_REPOS[kernel_url]['rev']
+    assert 'ref' not in kernel
+
+
+def test_moved_device_repo(tmpdir: Any, lineageos_dir: Any) -> None:
+    # As if FP4's repo had been renamed from a previous vendor name
+    write_device_metadata({'FP4': {**DEVICE_METADATA['FP4'], 'vendor': 'oldname'}})
+
+    search_results = {'items': [{'name': 'android_device_fairphone_FP4'}, {'name': 'android_kernel_fairphone_sm7225'}]}
+    run_update_device_dirs(tmpdir, github_api=search_results)
+
+    assert 'device/fairphone/FP4' in json.load(open('lineage-21.0/device-dirs.json'))
+    # The new vendor is recorded where update_device_metadata.py picks it up again
+    device_vendors, _ = update_device_metadata.load_vendor_overrides('vendor_overrides.toml')
+    assert device_vendors['FP4'] == 'fairphone'
+    assert json.load(open('device-metadata.json'))['FP4']['vendor'] == 'fairphone'
+
+
+def test_ambiguous_moved_device_repo(tmpdir: Any, lineageos_dir: Any, capsys: Any) -> None:
+    write_device_metadata({'FP4': {**DEVICE_METADATA['FP4'], 'vendor': 'oldname'}})
+    search_results = {'items': [{'name': 'android_device_fairphone_FP4'}, {'name': 'android_device_other_FP4'}]}
+
+    # Not guessed without an answer
+    run_update_device_dirs(tmpdir, github_api=search_results)
+    assert 'SKIP: FP4 repo moved to one of fairphone other' in capsys.readouterr().out
+    assert not os.path.exists('lineage-21.0/device-dirs.json')
+    assert not os.path.exists('vendor_overrides.toml')
+
+    with open('answers.toml', 'w') as f:
+        f.write('[vendors]\nFP4 = "fairphone"\n')
+    run_update_device_dirs(tmpdir, '--answers', 'answers.toml', github_api=search_results)
+    assert 'device/fairphone/FP4' in json.load(open('lineage-21.0/device-dirs.json'))
+    # Recorded, so later runs don't have to ask again
+    device_vendors, _ = update_device_metadata.load_vendor_overrides('vendor_overrides.toml')
+    assert device_vendors['FP4'] == 'fairphone'
+
+
+def test_interactive_vendor_choice() -> None:
+    choose_vendor = update_device_dirs.make_vendor_chooser(None, interactive=True)
+    with patch('builtins.input', side_effect=['nope', 'other']):
+        assert choose_vendor('FP4', ['fairphone', 'other']) == 'other'
+    with patch('builtins.input', return_value=''):
+        assert choose_vendor('FP4', ['fairphone', 'other']) is None
+    assert update_device_dirs.make_vendor_chooser(None, interactive=False)('FP4', ['fairphone', 'other']) is None
+
+
+def test_device_vendor_overrides(tmpdir: Any, lineageos_dir: Any) -> None:
+    # Metadata from before the override was added. The repo is found without searching GitHub
+    write_device_metadata({'FP4': {**DEVICE_METADATA['FP4'], 'vendor': 'oldname'}})
+    with open('vendor_overrides.toml', 'w') as f:
+        f.write('[devices]\nFP4 = "fairphone"\n')
+    run_update_device_dirs(tmpdir)
+
+    assert 'device/fairphone/FP4' in json.load(open('lineage-21.0/device-dirs.json'))
+
+
+def test_shards(tmpdir: Any, lineageos_dir: Any) -> None:
+    run_update_device_dirs(tmpdir)
+    unsharded = {name: json.load(open(f'lineage-21.0/{name}')) for name in ['device-dirs.json', 'vendor-dirs.json']}
+    for name in unsharded:
+        os.remove(f'lineage-21.0/{name}')
+
+    # With 3 shards, FP4 and cheeseburger end up in different ones and the third one is empty
+    for index in range(3):
+        run_update_device_dirs(tmpdir, '--shard', f'{index}/3')
+    fp4_shard = json.load(open('lineage-21.0/device-dirs.shard-0-of-3.json'))
+    assert sorted(fp4_shard) == ['device/fairphone/FP4', 'kernel/fairphone/sm7225']
+    assert json.load(open('lineage-21.0/vendor-dirs.shard-2-of-3.json')) == {}
+    assert not os.path.exists('lineage-21.0/device-dirs.json')
+
+    run_update_device_dirs(tmpdir, '--merge-shards')
+    for name, data in unsharded.items():
+        assert json.load(open(f'lineage-21.0/{name}')) == data
+
+    # A missing shard is an error rather than silently losing its devices
+    os.remove('lineage-21.0/device-dirs.shard-1-of-3.json')
+    with pytest.raises(ValueError, match='missing the outputs of shards 1 of 3'):
+        run_update_device_dirs(tmpdir, '--merge-shards')
+
+
+def test_shards_moved_device_repo(tmpdir: Any, lineageos_dir: Any) -> None:
+    write_device_metadata({**DEVICE_METADATA, 'FP4': {**DEVICE_METADATA['FP4'], 'vendor': 'oldname'}})
+
+    search_results = {'items': [{'name': 'android_device_fairphone_FP4'}]}
+    for index in range(2):
+        run_update_device_dirs(tmpdir, '--shard', f'{index}/2', github_api=search_results)
+
+    # The shared files are only updated when merging, since shards may run at the same time
+    assert json.load(open('device-metadata.json'))['FP4']['vendor'] == 'oldname'
+    assert not os.path.exists('vendor_overrides.toml')
+
+    run_update_device_dirs(tmpdir, '--merge-shards')
+    device_vendors, _ = update_device_metadata.load_vendor_overrides('vendor_overrides.toml')
+    assert device_vendors['FP4'] == 'fairphone'
+    assert json.load(open('device-metadata.json')) == with_nonfree(DEVICE_METADATA)
+
+
+def test_unreachable_repos(tmpdir: Any, lineageos_dir: Any, capsys: Any) -> None:
+    kernel_url = 'https://github.com/LineageOS/android_kernel_fairphone_sm7225'
+    cheeseburger_url = 'https://github.com/LineageOS/android_device_oneplus_cheeseburger'
+
+    # Retries ran out on transient errors for these
+    def ls_remote(url: str) -> Dict[str, str]:
+        if url in [kernel_url, cheeseburger_url]:
+            raise subprocess.CalledProcessError(128, ['git', 'ls-remote', url], stderr=b'fatal: early EOF')
+        return recorded_ls_remote(url)
+
+    run_update_device_dirs(tmpdir, '--foss-only', ls_remote=ls_remote)
+
+    output = capsys.readouterr().out
+    # An unreachable device repo isn't mistaken for a moved one (which would search GitHub)
+    assert 'WARNING: Failed to probe the following devices: cheeseburger' in output
+    assert f'could not be reached, running again later might help:\n  kernel/fairphone/sm7225 ({kernel_url})' in output
+    assert 'gone upstream' not in output
+
+
+def test_offline_uncached_device(tmpdir: Any, lineageos_dir: Any) -> None:
+    def ls_remote(url: str) -> Dict[str, str]:
+        if url == 'https://github.com/LineageOS/android_device_fairphone_FP4':
+            raise OfflineError(f"{url} isn't cached, but running with --offline")
+        return recorded_ls_remote(url)
+
+    # Stops the whole run instead of being reported as a device which failed to probe
+    with pytest.raises(OfflineError):
+        run_update_device_dirs(tmpdir, '--foss-only', ls_remote=ls_remote)
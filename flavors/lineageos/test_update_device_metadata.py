@@ -0,0 +1,122 @@
+# SPDX-FileCopyrightText: 2026 robotnix contributors
+# SPDX-License-Identifier: MIT
+
+import json
+import os
+
+from unittest.mock import patch
+
+from typing import Any, Dict
+
+import update_device_metadata
+
+# Excerpts of LineageOS/hudson from when the lineage-21.0 repos in test_update_device_dirs.py were recorded.
+# enchilada is a build target which supported_devices.toml doesn't list.
+RECORDED_BUILD_TARGETS = '''# device variant branch period
+
+cheeseburger userdebug lineage-21.0 W
+enchilada userdebug lineage-21.0 W
+FP4 userdebug lineage-21.0 W
+'''
+RECORDED_DEVICES_JSON = [
+    {'model': 'cheeseburger', 'oem': 'OnePlus', 'name': '5', 'has_recovery': True, 'lineage_recovery': False},
+    {'model': 'enchilada', 'oem': 'OnePlus', 'name': '6', 'has_recovery': False, 'lineage_recovery': True},
+    {'model': 'FP4', 'oem': 'Fairphone', 'name': 'Fairphone 4', 'has_recovery': False, 'lineage_recovery': False},
+]
+
+# What update_device_metadata.py makes of them
+RECORDED_METADATA = {
+    'FP4': {
+        'branch': 'lineage-21.0', 'lineage_recovery': False, 'name': 'Fairphone 4', 'oem': 'Fairphone',
+        'period': 'W', 'variant': 'userdebug', 'vendor': 'fairphone',
+    },
+    'cheeseburger': {
+        'branch': 'lineage-21.0', 'lineage_recovery': False, 'name': '5', 'oem': 'OnePlus',
+        'period': 'W', 'variant': 'userdebug', 'vendor': 'oneplus',
+    },
+}
+
+
+def hudson_checkout(tmpdir: Any) -> Dict[str, Any]:
+    path = tmpdir / 'hudson'
+    if not path.exists():
+        path.mkdir()
+        (path / 'lineage-build-targets').write(RECORDED_BUILD_TARGETS)
+        path.mkdir('updater')
+        (path / 'updater' / 'devices.json').write(json.dumps(RECORDED_DEVICES_JSON))
+    return {'url': 'https://github.com/LineageOS/hudson', 'path': str(path)}
+
+
+def run_update_device_metadata(tmpdir: Any, *args: str) -> None:
+    """Run update_device_metadata.py in the current dir against the recorded hudson checkout"""
+    with patch('update_device_metadata.checkout_git', side_effect=lambda url, rev: hudson_checkout(tmpdir)), \
+            patch('sys.argv', ['update_device_metadata.py', *args]):
+        update_device_metadata.main()
+
+
+def test_fetch_metadata(tmpdir: Any) -> None:
+    os.chdir(tmpdir.mkdir('lineageos'))
+    run_update_device_metadata(tmpdir)
+    # enchilada isn't in supported_devices.toml
+    assert json.load(open('device-metadata.json')) == RECORDED_METADATA
+
+
+def test_unlisted_device(tmpdir: Any) -> None:
+    os.chdir(tmpdir.mkdir('lineageos'))
+    with open('device-metadata.json', 'w') as f:
+        json.dump(RECORDED_METADATA, f)
+
+    # Without --allow-unlisted, asking for a device supported_devices.toml doesn't list changes nothing
+    run_update_device_metadata(tmpdir, '--device', 'enchilada')
+    assert json.load(open('device-metadata.json')) == RECORDED_METADATA
+
+    run_update_device_metadata(tmpdir, '--device', 'enchilada', '--allow-unlisted')
+    assert json.load(open('device-metadata.json')) == {
+        **RECORDED_METADATA,
+        'enchilada': {
+            'branch': 'lineage-21.0', 'lineage_recovery': True, 'name': '6', 'oem': 'OnePlus',
+            'period': 'W', 'variant': 'userdebug', 'vendor': 'oneplus',
+        },
+    }
+
+    # It still has to be a build target
+    run_update_device_metadata(tmpdir, '--device', 'nonexistent', '--allow-unlisted')
+    assert 'nonexistent' not in json.load(open('device-metadata.json'))
+
+
+def test_keep_nonfree(tmpdir: Any) -> None:
+    os.chdir(tmpdir.mkdir('lineageos'))
+    # As recorded by update_device_dirs.py. cheeseburger's is from a branch it isn't on anymore
+    with open('device-metadata.json', 'w') as f:
+        json.dump({
+            'FP4': {**RECORDED_METADATA['FP4'], 'nonfree': ['vendor/fairphone/FP4']},
+            'cheeseburger': {
+                **RECORDED_METADATA['cheeseburger'],
+                'branch': 'lineage-20.0',
+                'nonfree': ['vendor/oneplus'],
+            },
+        }, f)
+
+    run_update_device_metadata(tmpdir)
+    assert json.load(open('device-metadata.json')) == {
+        **RECORDED_METADATA,
+        'FP4': {**RECORDED_METADATA['FP4'], 'nonfree': ['vendor/fairphone/FP4']},
+    }
+
+
+def test_device_aliases(tmpdir: Any) -> None:
+    os.chdir(tmpdir.mkdir('lineageos'))
+    with open('vendor_overrides.toml', 'w') as f:
+        f.write('[aliases]\n"fp4-retail" = "FP4"\n')
+
+    assert update_device_metadata.resolve_device_names(
+        RECORDED_METADATA, ['fp4-retail', 'OnePlus 5', 'fairphone 4', 'fp4', 'sunfish'],
+        update_device_metadata.load_device_aliases('vendor_overrides.toml'),
+    ) == ['FP4', 'cheeseburger', 'FP4', 'FP4', 'sunfish']
+
+    # Only the device given by its alias is updated
+    outdated = {device: {**data, 'branch': 'lineage-20.0'} for device, data in RECORDED_METADATA.items()}
+    with open('device-metadata.json', 'w') as f:
+        json.dump(outdated, f)
+    run_update_device_metadata(tmpdir, '--device', 'fp4-retail')
+    assert json.load(open('device-metadata.json')) == {**outdated, 'FP4': RECORDED_METADATA['FP4']}
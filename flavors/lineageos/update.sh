@@ -6,21 +6,53 @@ set -euo pipefail
 
 cd "$(dirname "${BASH_SOURCE[0]}")"
 
-branch=$1
-
-args=(
-    --cache-search-path ../../
-    --ref-type branch
-    "https://github.com/LineageOS/android"
-    "$branch"
-)
+if [[ $# -eq 0 ]]; then
+    echo "Usage: $0 <branch>..." >&2
+    exit 1
+fi
 
 export TMPDIR=/var/tmp
+# Device repos are queried again for every branch, so reuse ls-remote results within a run. The cache
+# is removed again at the end, so the next run sees what changed upstream in the meantime. Set
+# ROBOTNIX_LS_REMOTE_CACHE_TTL to keep using the one in ~/.cache/robotnix across runs instead.
+run_cache_dir=""
+if [[ -z "${ROBOTNIX_LS_REMOTE_CACHE_TTL:-}" ]]; then
+    run_cache_dir="$(mktemp -d "${TMPDIR}/robotnix-run-cache-XXXXXX")"
+    export ROBOTNIX_LS_REMOTE_CACHE_FILE="${run_cache_dir}/ls-remote.json"
+    export ROBOTNIX_LS_REMOTE_CACHE_TTL=86400
+fi
+# Don't check out hudson and unchanged repos again if an earlier run already did
+export ROBOTNIX_CHECKOUT_CACHE="${ROBOTNIX_CHECKOUT_CACHE:-1}"
+
+# Stage all output files and only move them into place once every branch is done. That way an
+# interrupted run can't leave device-metadata.json out of sync with the device and vendor dirs.
+if [[ -z "${ROBOTNIX_STAGING_DIR:-}" ]]; then
+    ROBOTNIX_STAGING_DIR="$(mktemp -d "${TMPDIR}/robotnix-staging-XXXXXX")"
+    export ROBOTNIX_STAGING_DIR
+fi
+on_exit() {
+    [[ -z "$run_cache_dir" ]] || rm -rf "$run_cache_dir"
+    [[ ! -d "$ROBOTNIX_STAGING_DIR" ]] || echo "Update did not finish. To resume it, run again with ROBOTNIX_STAGING_DIR=$ROBOTNIX_STAGING_DIR" >&2
+}
+trap on_exit EXIT
 
+# Device metadata is shared by all branches, so only fetch it once
 ./update_device_metadata.py
-../../scripts/mk_repo_file.py --out "${branch}/repo.json" "${args[@]}"
-./update_device_dirs.py --branch "$branch"
+
+for branch in "$@"; do
+    args=(
+        --cache-search-path ../../ "$ROBOTNIX_STAGING_DIR"
+        --ref-type branch
+        "https://github.com/LineageOS/android"
+        "$branch"
+    )
+
+    ../../scripts/mk_repo_file.py --out "${branch}/repo.json" "${args[@]}"
+    ./update_device_dirs.py --branch "$branch"
+done
+
+../../scripts/commit_staged.py "$ROBOTNIX_STAGING_DIR"
 
 endEpoch="$(date +%s)"
 echo "$endEpoch" > lastUpdated.epoch
-echo Updated branch "$branch". End epoch: "$endEpoch"
+echo "Updated branches $*. End epoch: $endEpoch"
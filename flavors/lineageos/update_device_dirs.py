@@ -4,131 +4,412 @@
 
 import argparse
 import copy
+import glob
 import json
+import multiprocessing
+import multiprocessing.pool
 import os
 import pathlib
+import re
+import subprocess
+import sys
+import threading
+import tomllib
+import urllib.parse
 
-from typing import Any, Callable, Dict, List, Optional, cast
+from typing import Any, Callable, Dict, List, Optional, Set, Tuple, cast
 
-from robotnix_common import save, get_store_path, checkout_git, ls_remote, get_mirrored_url, check_free_space, GitCheckoutInfoDict
+from robotnix_common import save, load, get_store_path, checkout_git, ls_remote, get_mirrored_url, check_free_space, \
+    check_up_to_date, current_path, github_api, add_common_args, apply_common_args, run_main, normalize_url, in_shard, \
+    is_permanent_error, parse_shard, GitCheckoutInfoDict, OfflineError
+from update_device_metadata import filter_devices, add_device_vendor_override, load_vendor_overrides, \
+    load_device_aliases, resolve_device_names, DEVICE_VENDORS, VENDOR_OVERRIDES
 
 # A full run took approximately 12 minutes total. Needed to set TMPDIR=/tmp
 #
 # TODO: Output a timestamp somewhere
-# TODO: Optionally parallelize fetching
 
 debug = False
 
+# Relpaths whose pinned revision changed during this run
+changed_relpaths: List[str] = []
+
 # Project info is just GitCheckoutInfoDict plus deps
 class ProjectInfoDict(GitCheckoutInfoDict, total=False):
     deps: List[str]
+    overrideRef: str
+    ref: str  # Only if lineage.dependencies asks for something other than the branch being updated
+
+# Branches on which TheMuppets has a separate vendor repo per device, instead of one per vendor
+PER_DEVICE_VENDOR_BRANCHES = [ 'lineage-20.0', 'lineage-21.0', 'lineage-22.1' ]
+
+# Dirs already pinned on any branch, keyed by (normalized url, rev). Repos shared between branches
+# are often at the same revision on several of them, and don't need to be prefetched again for each.
+known_pins: Dict[Tuple[str, str], ProjectInfoDict] = {}
+
+
+def read_known_pins() -> None:
+    known_pins.clear()
+    for filename in sorted(glob.glob('*/device-dirs.json') + glob.glob('*/vendor-dirs.json')):
+        for dir_info in load(filename).values():
+            if 'overrideRef' not in dir_info:
+                known_pins[normalize_url(dir_info['url']), dir_info['rev']] = dir_info
+
 
-def fetch_relpath(dirs: Dict[str, Any], relpath: str, url: str, branch: str) -> ProjectInfoDict:
+# Refs can be given as a branch name, a full ref (like refs/tags/...) or a commit hash
+def normalize_ref(ref: str) -> str:
+    if ref.startswith('refs/') or re.fullmatch('[0-9a-f]{40}', ref):
+        return ref
+    return f'refs/heads/{ref}'
+
+
+# argparse type of --override RELPATH=REF
+def parse_override(value: str) -> Tuple[str, str]:
+    relpath, sep, ref = value.partition('=')
+    if sep == '' or relpath.strip('/') == '' or ref == '':
+        raise argparse.ArgumentTypeError(f"invalid override {value}, expected RELPATH=REF")
+    return relpath.strip('/'), normalize_ref(ref)
+
+
+def fetch_relpath(dirs: Dict[str, Any], relpath: str, url: str, branch: str,
+                  override_ref: Optional[str] = None) -> ProjectInfoDict:
     if debug:
         print(f'Trying to fetch {relpath}')
     orig_url = url
     url = get_mirrored_url(url)
 
     current_rev = dirs.get(relpath, {}).get('rev', None)
-    refs = ls_remote(url)
-    ref = f'refs/heads/{branch}'
-    if ref not in refs:
-        raise ValueError(f'{url} is missing {ref}')
-    newest_rev = refs[ref]
+    ref = override_ref if override_ref is not None else f'refs/heads/{branch}'
+    if re.fullmatch('[0-9a-f]{40}', ref):
+        # Pinned to a commit, there's nothing to resolve
+        newest_rev = ref
+    else:
+        refs = ls_remote(url)
+        if ref not in refs:
+            raise ValueError(f'{url} is missing {ref}')
+        # Annotated tags point to a tag object, ls-remote lists the commit it points to as <tag>^{}
+        newest_rev = refs.get(f'{ref}^{{}}', refs[ref])
     if current_rev != newest_rev:
         if debug:
             print(f'Previous data did not contain up-to-date {relpath}, fetching')
-        dirs[relpath] = checkout_git(url, ref)
+        known_pin = known_pins.get((normalize_url(orig_url), newest_rev))
+        if known_pin is not None:
+            if debug:
+                print(f'Reusing {relpath} pinned at the same revision on another branch')
+            dirs[relpath] = copy.deepcopy(known_pin)
+        else:
+            dirs[relpath] = checkout_git(url, ref)
         dirs[relpath]['url'] = orig_url
+
+        new_date = dirs[relpath]['date']
+        if current_rev is None:
+            print(f'{relpath}: new at {newest_rev[:12]} ({new_date})')
+        else:
+            print(f'{relpath}: {current_rev[:12]} -> {newest_rev[:12]} ({new_date})')
+        changed_relpaths.append(relpath)
     else:
         print(relpath + ' is up to date.')
 
     return cast(ProjectInfoDict, dirs[relpath])
 
 
+# Search the GitHub org behind url_base for repos named android_device_<vendor>_<device> and return
+# the <vendor>s. This catches devices whose repo was renamed upstream (e.g. on a vendor rebrand).
+def find_moved_device_vendors(url_base: str, device: str) -> List[str]:
+    github_prefix = 'https://github.com/'
+    if not url_base.startswith(github_prefix):
+        return []
+    org = url_base[len(github_prefix):].strip('/')
+
+    query = urllib.parse.quote(f'{device} in:name org:{org}')
+    results = github_api(f'search/repositories?q={query}&per_page=100')
+
+    pattern = re.compile(f'android_device_(.+)_{re.escape(device)}')
+    matches = (pattern.fullmatch(repo['name']) for repo in results['items'])
+    return sorted(set(match.group(1) for match in matches if match))
+
+
+# Picks the vendor a device repo moved to when several repos match, or None to skip the device
+VendorChooser = Callable[[str, List[str]], Optional[str]]
+
+
+# Answer from a TOML file with a [vendors] table mapping devices to vendors, for non-interactive runs.
+# Falls back to asking on the terminal if interactive
+def make_vendor_chooser(answers_file: Optional[str], interactive: bool) -> VendorChooser:
+    answers: Dict[str, str] = {}
+    if answers_file is not None:
+        answers = tomllib.loads(open(answers_file).read()).get('vendors', {})
+    # Probing runs in a thread pool, only ask one question at a time
+    lock = threading.Lock()
+
+    def choose_vendor(device: str, candidates: List[str]) -> Optional[str]:
+        if device in answers:
+            if answers[device] in candidates:
+                return answers[device]
+            print(f"WARNING: the answer {answers[device]} for {device} is none of {' '.join(candidates)}")
+        if not interactive:
+            return None
+        with lock:
+            while True:
+                answer = input(f"Which vendor did {device}'s repo move to? ({'/'.join(candidates)}, "
+                               "empty to skip it) ").strip()
+                if answer == '' or answer in candidates:
+                    return answer or None
+    return choose_vendor
+
+
 # Fetch device source trees for devices in metadata
 def fetch_device_dirs(metadata: Any,
                       url_base: str,
                       branch: str,
                       prev_data: Optional[Any] = None,
-                      callback: Optional[Callable[[Any], Any]] = None
+                      callback: Optional[Callable[[Any], Any]] = None,
+                      jobs: int = 1,
+                      overrides: Optional[Dict[str, str]] = None,
+                      device_vendors: Dict[str, str] = DEVICE_VENDORS,
+                      choose_vendor: Optional[VendorChooser] = None,
                       ) -> Dict[str, ProjectInfoDict]:
     dirs: Dict[str, ProjectInfoDict]
+    if overrides is None:
+        overrides = {}
 
     if prev_data is not None:
         dirs = copy.deepcopy(prev_data)
     else:
         dirs = {}
 
-    dirs_to_fetch = set()  # Pairs of (relpath, url)
-    dirs_fetched = set()  # Just strings of relpath
-    for device, data in metadata.items():
+    # Find the device repo for a single device. Returns (relpath, url) if it has our branch
+    def probe_device(item: Tuple[str, Any]) -> Optional[Tuple[str, str]]:
+        device, data = item
         if debug:
             print(data)
 
-        # They're google devices but their vendor is askey for some reason
-        if device in [ 'deadpool', 'wade' ]:
-            vendor = 'askey'
-        elif device == 'debx':
-            vendor = 'asus'
-        else:
-            vendor = data['vendor']
+        # The same per-device vendor overrides update_device_metadata.py uses, in case the metadata
+        # (or a product given on the command line) predates them
+        vendor = device_vendors.get(device, data['vendor'])
 
         url = f'{url_base}/android_device_{vendor}_{device}'
 
         if debug:
             print(url)
 
-        refs = ls_remote(url)
+        try:
+            refs = ls_remote(url)
+        except subprocess.CalledProcessError as e:
+            if not is_permanent_error(e):
+                raise
+            candidates = find_moved_device_vendors(url_base, device)
+            if len(candidates) == 0:
+                print(f'SKIP: could not find a device repo for {device}')
+                return None
+            moved_vendor = candidates[0]
+            if len(candidates) > 1:
+                chosen = choose_vendor(device, candidates) if choose_vendor is not None else None
+                if chosen is None:
+                    print(f"SKIP: {device} repo moved to one of {' '.join(candidates)}. Pick one with "
+                          "--interactive or --answers, or add it to the [devices] vendor overrides")
+                    return None
+                moved_vendor = chosen
+            print(f'NOTE: {device} repo moved from {vendor} to {moved_vendor}')
+            vendor = moved_vendor
+            data['vendor'] = vendor
+            url = f'{url_base}/android_device_{vendor}_{device}'
+            refs = ls_remote(url)
+
         if f'refs/heads/{branch}' in refs:
-            dirs_to_fetch.add((f'device/{vendor}/{device}', url))
+            return (f'device/{vendor}/{device}', url)
         else:
             print(f'SKIP: {branch} branch does not exist for {device}')
+            return None
+
+    failed_devices: List[str] = []
 
-    while len(dirs_to_fetch) > 0:
-        relpath, url = dirs_to_fetch.pop()
+    # Don't let a single device's failure abort probing all the others
+    def probe_device_isolated(item: Tuple[str, Any]) -> Optional[Tuple[str, str]]:
         try:
-            dir_info = fetch_relpath(dirs, relpath, url, branch)
-        except ValueError:
-            continue
+            return probe_device(item)
+        except OfflineError:
+            # Not a problem of this device, and run_main() tells how to fix it
+            raise
+        except Exception as e:
+            print(f'ERROR: failed to probe {item[0]}: {e}')
+            failed_devices.append(item[0])
+            return None
+
+    with multiprocessing.pool.ThreadPool(jobs) as pool:
+        probed = pool.map(probe_device_isolated, metadata.items())
+
+    # Refs that lineage.dependencies asks for, if not our branch (None). Dirs whose dependents weren't
+    # fetched again in this run aren't in here, those keep the ref recorded in their entry.
+    dep_refs: Dict[str, Optional[str]] = {}
+
+    def get_ref(relpath: str) -> Optional[str]:
+        if relpath in overrides:
+            return overrides[relpath]
+        if relpath in dep_refs:
+            return dep_refs[relpath]
+        return dirs.get(relpath, {}).get('ref')
+
+    # Fetch a single dir without touching the shared dirs dict, which is only updated (and saved
+    # through callback) from the main thread below
+    def fetch_one(item: Tuple[str, str]) -> Tuple[str, str, Optional[ProjectInfoDict], Optional[Exception]]:
+        relpath, url = item
+        single_dir = {relpath: dirs[relpath]} if relpath in dirs else {}
+        try:
+            return (relpath, url, fetch_relpath(single_dir, relpath, url, branch, get_ref(relpath)), None)
+        except (ValueError, subprocess.CalledProcessError) as e:
+            return (relpath, url, None, e)
 
-        # Also grab any dirs that this one depends on
-        lineage_dependencies_filename = get_store_path(os.path.join(dir_info['path'], 'lineage.dependencies'))
-        if os.path.exists(lineage_dependencies_filename):
-            if debug:
-                print(f'found deps {lineage_dependencies_filename}')
-            lineage_dependencies = json.load(open(lineage_dependencies_filename))
-
-            for dep in lineage_dependencies:
-                if dep['target_path'] not in dirs_fetched:
-                    dirs_to_fetch.add((dep['target_path'], f"{url_base}/{dep['repository']}"))
-
-            dir_info['deps'] = [dep['target_path'] for dep in lineage_dependencies]
-        # If the path doesn't exist, there are two cases:
-        # 1. We just fetched the dir and lineage.dependencies does not exist
-        # 2. We didn't fetch the dir because it was up-to-date already but didn't have it in the store either
-        # In 1. we want deps to be an empty list.
-        # In 2. the dict would already contain 'deps' and those should remain as they were.
-        elif 'deps' not in dir_info:
-            dir_info['deps'] = []
-
-        if callback is not None:
-            callback(dirs)
-        dirs_fetched.add(relpath)
+    dirs_to_fetch = set(result for result in probed if result is not None)  # Pairs of (relpath, url)
+    dirs_fetched = set()  # Just strings of relpath
+    dirs_gone_upstream = set()  # Pairs of (relpath, url)
+    dirs_unreachable = set()  # Pairs of (relpath, url), failing with transient errors even after retrying
+    # Dependencies are only known once a dir is fetched, so fetch in rounds: every dir we know
+    # about in parallel, then everything those depend on, and so on.
+    with multiprocessing.pool.ThreadPool(jobs) as pool:
+        while len(dirs_to_fetch) > 0:
+            current_round = sorted(dirs_to_fetch)
+            dirs_to_fetch = set()
+            dirs_fetched.update(relpath for relpath, _ in current_round)
+
+            for relpath, url, dir_info, error in pool.imap_unordered(fetch_one, current_round):
+                if isinstance(error, subprocess.CalledProcessError):
+                    # Either the repo itself is gone (e.g. deleted or renamed upstream), not just the branch,
+                    # or it couldn't be reached. Keep whatever we had for it before, so the last known good
+                    # revision stays pinned.
+                    if is_permanent_error(error):
+                        dirs_gone_upstream.add((relpath, url))
+                    else:
+                        dirs_unreachable.add((relpath, url))
+                    continue
+                if dir_info is None:
+                    continue
+                dep_ref = dep_refs[relpath] if relpath in dep_refs else dirs.get(relpath, {}).get('ref')
+                dirs[relpath] = dir_info
+                # Recorded so that --frozen flags it, and cleared again by the next run without --override
+                if relpath in overrides:
+                    dir_info['overrideRef'] = overrides[relpath]
+                else:
+                    dir_info.pop('overrideRef', None)
+                if dep_ref is not None:
+                    dir_info['ref'] = dep_ref
+                else:
+                    dir_info.pop('ref', None)
+
+                # Also grab any dirs that this one depends on
+                lineage_dependencies_filename = get_store_path(os.path.join(dir_info['path'], 'lineage.dependencies'))
+                if os.path.exists(lineage_dependencies_filename):
+                    if debug:
+                        print(f'found deps {lineage_dependencies_filename}')
+                    lineage_dependencies = json.load(open(lineage_dependencies_filename))
+
+                    for dep in lineage_dependencies:
+                        dep_ref = normalize_ref(dep['branch']) if 'branch' in dep else None
+                        dep_refs[dep['target_path']] = dep_ref if dep_ref != f'refs/heads/{branch}' else None
+                        if dep['target_path'] not in dirs_fetched:
+                            dirs_to_fetch.add((dep['target_path'], f"{url_base}/{dep['repository']}"))
+
+                    dir_info['deps'] = [dep['target_path'] for dep in lineage_dependencies]
+                # If the path doesn't exist, there are two cases:
+                # 1. We just fetched the dir and lineage.dependencies does not exist
+                # 2. We didn't fetch the dir because it was up-to-date already but didn't have it in the store either
+                # In 1. we want deps to be an empty list.
+                # In 2. the dict would already contain 'deps' and those should remain as they were.
+                elif 'deps' not in dir_info:
+                    dir_info['deps'] = []
+
+                if callback is not None:
+                    callback(dirs)
+
+    for relpath in sorted(overrides.keys() - dirs_fetched):
+        print(f'WARNING: --override for {relpath} was not used, since no device depends on it')
+
+    if len(failed_devices) > 0:
+        print(f'WARNING: Failed to probe the following devices: {" ".join(sorted(failed_devices))}')
+
+    for failed, description in [(dirs_gone_upstream, 'are gone upstream'),
+                                (dirs_unreachable, 'could not be reached, running again later might help')]:
+        if len(failed) > 0:
+            print(f'WARNING: The following dependencies {description}:')
+        for relpath, url in sorted(failed):
+            if relpath in dirs:
+                print(f'  {relpath} ({url}), keeping last known rev {dirs[relpath]["rev"]}')
+            else:
+                print(f'  {relpath} ({url}), no previous rev known')
 
     return dirs
 
 
-def fetch_vendor_dirs(metadata: Any,
-                      url_base: str,
-                      branch: str,
-                      true_branch: str,
-                      device_dirs: Any,
-                      prev_data: Optional[Any] = None,
-                      callback: Optional[Callable[[Any], Any]] = None,
-                      ) -> Any:
-    required_vendor = set()
+# Matches device/kernel tree references in makefiles, like "$(call inherit-product, device/<vendor>/<name>/...)",
+# "include device/<vendor>/<name>/..." and "TARGET_KERNEL_SOURCE := kernel/<vendor>/<name>"
+TREE_REFERENCE_RE = re.compile(
+    r'(?:inherit-product(?:-if-exists)?,\s*|^-?include\s+|TARGET_KERNEL_SOURCE\s*:?=\s*)'
+    r'((?:device|kernel)/[^/\s)]+/[^/\s)]+)', re.MULTILINE)
+
+
+def relpath_with_deps(dirs: Dict[str, ProjectInfoDict], relpath: str) -> Set[str]:
+    result: Set[str] = set()
+    to_visit = [relpath]
+    while len(to_visit) > 0:
+        current = to_visit.pop()
+        if current in result:
+            continue
+        result.add(current)
+        to_visit.extend(dirs.get(current, {}).get('deps', []))
+    return result
+
+
+# Cross-check the makefiles in each fetched device tree against its resolved dependencies.
+# Returns warnings for referenced trees that neither lineage.dependencies nor the repo manifest provide.
+def lint_device_dirs(dirs: Dict[str, ProjectInfoDict], repo_dirs: Dict[str, Any]) -> List[str]:
+    warnings: List[str] = []
+    for relpath, dir_info in sorted(dirs.items()):
+        if not relpath.startswith('device/'):
+            continue
+        # The store path might be missing if the dir was up to date and never fetched on this machine
+        path = get_store_path(dir_info['path'])
+        if not os.path.isdir(path):
+            continue
+
+        available = relpath_with_deps(dirs, relpath) | set(repo_dirs.keys())
+        missing: Set[str] = set()
+        for root, _, files in os.walk(path):
+            for filename in files:
+                if not filename.endswith('.mk'):
+                    continue
+                text = open(os.path.join(root, filename), errors='replace').read()
+                missing.update(ref for ref in TREE_REFERENCE_RE.findall(text) if ref not in available)
+
+        for ref in sorted(missing):
+            warnings.append(f'{relpath} references {ref}, which is not in its lineage.dependencies')
+    return warnings
+
+
+# Get the URL of TheMuppets' proprietary repo for vendor/<vendor_relpath>.
+# Some of them only live in the GitLab mirror, depending on the branch.
+def get_muppets_url(url_base: str, branch: str, vendor_relpath: str) -> str:
+    real_url_base = url_base
+    if branch != 'lineage-21.0':
+        # Only some of google's devices are on gitlab...
+        gitlab_vendors = [ 'google/bluejay', 'google/cheetah', 'google/oriole', 'google/panther', 'google/raven',
+                           'google/lynx', 'google/tangorpro' ]
+        # Two motorola devices are /not/ on gitlab!
+        # TODO perhaps invert this list, new devices seem to be added to github now
+        motorola_gitlab = vendor_relpath.startswith('motorola/') and vendor_relpath not in [
+            'motorola/nio', 'motorola/pstar', 'motorola/devon', 'motorola/rhode', 'motorola/hawao',
+            'motorola/sm8250-common', 'motorola/sm6225-common' ]
+        gitlab_on_20 = motorola_gitlab or vendor_relpath in gitlab_vendors
+        if vendor_relpath == 'xiaomi' or (branch == 'lineage-20.0' and gitlab_on_20):
+            real_url_base = "https://gitlab.com/the-muppets"
+
+    return f"{real_url_base}/proprietary_vendor_{vendor_relpath.replace('/', '_')}"
+
+
+# Find the vendor/ dirs (relative to vendor/) with proprietary blobs that each device needs
+def get_required_vendor_dirs(metadata: Any, branch: str, device_dirs: Any) -> Dict[str, Set[str]]:
+    required_vendor: Dict[str, Set[str]] = {}
     for device, data in metadata.items():
+        required_vendor[device] = set()
         if debug:
             print("DEVICE: ", device)
             print("data: ", data)
@@ -142,24 +423,13 @@ def fetch_vendor_dirs(metadata: Any,
             if debug:
                 print("branch: ", branch)
 
-            # Branches that we fetch vendor dirs for
-            #
-            # TODO this needs to be a lot more robust w.r.t. future versions.
-            # We should instead check whether there is a branch for each device
-            # rather than relying on metadata here because some devices are
-            # supported on many branches but metadata only includes the newest
-            # one which might also be newer than the newest we support.
-            #
-            # HACK Allow devices that are 21.0 and 22.1, eventhough some of them won't have a branch for 20.0.
-            supported_branches = [ 'lineage-20.0', 'lineage-21.0', 'lineage-22.1' ]
-
-            if branch in supported_branches:
-                if 'branch' in data and data['branch'] in supported_branches:
-                    required_vendor.add(os.path.join(vendor, device))
-                else:
-                    print(f'SKIP: {device} is not available for {branch}')
+            # Whether the repo actually has our branch is probed before fetching below, since
+            # metadata only includes the newest branch a device is on, while many devices are
+            # supported on several branches.
+            if branch in PER_DEVICE_VENDOR_BRANCHES:
+                required_vendor[device].add(os.path.join(vendor, device))
             else:
-                required_vendor.add(vendor)
+                required_vendor[device].add(vendor)
 
         if 'vendor' in data:
             # Some devices need an additional vendor dir for their SoC.
@@ -183,10 +453,39 @@ def fetch_vendor_dirs(metadata: Any,
                     # Nvidia and zuk don't follow this pattern (obviously...)
                     if dep.endswith('-common') and not excluded:
                         relpath = dep.replace('device/', '')
-                        print("added vendor dep", relpath)
-                        required_vendor.add(relpath)
+                        if debug:
+                            print("added vendor dep", relpath)
+                        required_vendor[device].add(relpath)
+
+    return required_vendor
 
 
+# Record in each device's metadata which proprietary vendor dirs it needs, as 'nonfree'. Only for the
+# devices on branch whose device tree is in device_dirs, since that's what decides it
+def with_nonfree(metadata: Any, branch: str, device_dirs: Any) -> Any:
+    on_branch = {device: data for device, data in metadata.items()
+                 if data.get('branch') == branch and f"device/{data['vendor']}/{device}" in device_dirs}
+    required_vendor = get_required_vendor_dirs(on_branch, branch, device_dirs)
+    return {
+        device: {**data, 'nonfree': sorted(f'vendor/{p}' for p in required_vendor[device])}
+        if device in required_vendor else data
+        for device, data in metadata.items()
+    }
+
+
+def fetch_vendor_dirs(metadata: Any,
+                      url_base: str,
+                      branch: str,
+                      true_branch: str,
+                      device_dirs: Any,
+                      prev_data: Optional[Any] = None,
+                      callback: Optional[Callable[[Any], Any]] = None,
+                      url_template: Optional[str] = None,
+                      jobs: int = 1,
+                      ) -> Any:
+    required_vendor: Set[str] = set()
+    for vendor_relpaths in get_required_vendor_dirs(metadata, branch, device_dirs).values():
+        required_vendor |= vendor_relpaths
 
     if prev_data is not None:
         dirs = copy.deepcopy(prev_data)
@@ -196,35 +495,103 @@ def fetch_vendor_dirs(metadata: Any,
     if debug:
         print("previous data: ", prev_data)
         print("required_vendor: ", required_vendor)
-    for vendor in required_vendor:
-        relpath = f'vendor/{vendor}'
 
-        real_url_base = url_base
-        if branch != 'lineage-21.0':
-            # Only some of google's devices are on gitlab...
-            gitlab_vendors = [ 'google/bluejay', 'google/cheetah', 'google/oriole', 'google/panther', 'google/raven', 'google/lynx', 'google/tangorpro' ]
-            # Two motorola devices are /not/ on gitlab! TODO perhaps invert this list, new devices seem to be added to github now
-            motorola_gitlab = vendor.startswith('motorola/') and vendor not in [ 'motorola/nio', 'motorola/pstar', 'motorola/devon', 'motorola/rhode', 'motorola/hawao', 'motorola/sm8250-common', 'motorola/sm6225-common' ]
-            if vendor == 'xiaomi' or (branch == 'lineage-20.0' and (motorola_gitlab or vendor in gitlab_vendors)):
-                real_url_base = "https://gitlab.com/the-muppets"
+    # Like fetch_one in fetch_device_dirs, this leaves the shared dirs dict to the main thread
+    def fetch_vendor(vendor: str) -> Tuple[str, Optional[ProjectInfoDict]]:
+        relpath = f'vendor/{vendor}'
 
-        to_fetch = [ f"{real_url_base}/proprietary_{relpath.replace('/', '_')}" ];
+        if url_template is not None:
+            url = url_template.format(vendor=vendor.replace('/', '_'), branch=true_branch)
+        else:
+            url = get_muppets_url(url_base, branch, vendor)
 
-        for url in to_fetch:
-            print("trying git ls-remote ", url)
+        print(f"trying git ls-remote {url}")
+        try:
             refs = ls_remote(url)
-            if f'refs/heads/{true_branch}' in refs:
-                fetch_relpath(dirs, relpath, url, true_branch)
-                if callback is not None:
-                    callback(dirs)
+        except subprocess.CalledProcessError as e:
+            if is_permanent_error(e):
+                print(f'SKIP: {url} does not exist')
             else:
-                print(f'SKIP: {branch} branch does not exist for {url}')
-        print("")
+                print(f'WARNING: {url} could not be reached, keeping the previous {relpath} if any')
+            return (relpath, None)
+        if f'refs/heads/{true_branch}' not in refs:
+            print(f'SKIP: {branch} branch does not exist for {url}')
+            return (relpath, None)
+        single_dir = {relpath: dirs[relpath]} if relpath in dirs else {}
+        return (relpath, fetch_relpath(single_dir, relpath, url, true_branch))
+
+    # TheMuppets' repos are the largest downloads, so they profit the most from fetching in parallel
+    with multiprocessing.pool.ThreadPool(jobs) as pool:
+        for relpath, dir_info in pool.imap_unordered(fetch_vendor, sorted(required_vendor)):
+            if dir_info is None:
+                continue
+            dirs[relpath] = dir_info
+            if callback is not None:
+                callback(dirs)
     print("\n\n\n\n")
 
     return dirs
 
 
+# Output file of a single shard, like lineage-21.0/device-dirs.shard-0-of-4.json
+def shard_filename(filename: str, shard: Tuple[int, int]) -> str:
+    base, ext = os.path.splitext(filename)
+    return f'{base}.shard-{shard[0]}-of-{shard[1]}{ext}'
+
+
+# Record the new vendors of device repos found under another vendor, given as { device: vendor }.
+# The overrides make update_device_metadata.py keep them. device-metadata.json is updated right away
+# as well, so it matches the device dirs fetched
+def record_moved_vendors(vendor_overrides: str, moved: Dict[str, str]) -> None:
+    for device, vendor in sorted(moved.items()):
+        add_device_vendor_override(vendor_overrides, device, vendor)
+    print(f"Recorded the new vendor of {' '.join(sorted(moved))} in {vendor_overrides}")
+    metadata = load('device-metadata.json')
+    for device, vendor in moved.items():
+        metadata[device] = {**metadata.get(device, {}), 'vendor': vendor}
+    save('device-metadata.json', metadata)
+
+
+# Combine the outputs of every shard of a branch into device-dirs.json and vendor-dirs.json, and
+# record the vendors of moved device repos the shards found. Returns the shard files merged
+def merge_shards(branch: str, vendor_overrides: str) -> List[str]:
+    shard_files = sorted(glob.glob(os.path.join(branch, '*-dirs.shard-*-of-*.json')))
+    counts = set()
+    found: Dict[str, Set[int]] = {}
+    for filename in shard_files:
+        match = re.fullmatch(r'(.*)\.shard-(\d+)-of-(\d+)\.json', filename)
+        assert match is not None
+        counts.add(int(match.group(3)))
+        found.setdefault(f'{match.group(1)}.json', set()).add(int(match.group(2)))
+    if len(counts) == 0:
+        raise ValueError(f'no shard outputs found in {branch}')
+    if len(counts) > 1:
+        raise ValueError(f'shard outputs in {branch} are from runs with different shard counts: {sorted(counts)}')
+    count = counts.pop()
+    for filename, indices in sorted(found.items()):
+        missing = sorted(set(range(count)) - indices)
+        if len(missing) > 0:
+            raise ValueError(f"{filename}: missing the outputs of shards {' '.join(map(str, missing))} of {count}")
+
+    for filename in sorted(found):
+        # Dirs needed by devices of several shards were fetched by all of them, at the same revision
+        merged = load(filename, {})
+        for index in range(count):
+            merged.update(load(shard_filename(filename, (index, count))))
+        save(filename, merged)
+
+    # Only written by shards which found moved device repos
+    moved_files = sorted(glob.glob(os.path.join(branch, f'moved-vendors.shard-*-of-{count}.json')))
+    moved: Dict[str, str] = {}
+    for filename in moved_files:
+        moved.update(load(filename))
+    if len(moved) > 0:
+        record_moved_vendors(vendor_overrides, moved)
+    device_dirs_fn = os.path.join(branch, 'device-dirs.json')
+    save('device-metadata.json', with_nonfree(load('device-metadata.json'), branch, load(device_dirs_fn, {})))
+    return shard_files + moved_files
+
+
 def main() -> None:
     check_free_space()
 
@@ -234,19 +601,67 @@ def main() -> None:
                         help='product to fetch directory metadata for, specified by <vendor>_<device> '
                         '(example: google_crosshatch) '
                         'If no products are specified, all products in device-metadata.json will be updated')
+    parser.add_argument('--device', action='append', default=[],
+                        help="only update this device from device-metadata.json. Also takes alternate codenames "
+                        "and marketing names, like update_device_metadata.py. Can be given multiple times")
+    parser.add_argument('--vendor', action='append', default=[],
+                        help="only update devices of this vendor from device-metadata.json. "
+                        "Can be given multiple times")
     parser.add_argument('--debug', action='store_true', help="print debug info", default=False)
+    parser.add_argument('--jobs', '-j', default=multiprocessing.cpu_count(), type=int, help="number of concurrent jobs")
+    add_common_args(parser)
+    parser.add_argument('--frozen', action='store_true',
+                        help="don't write any files, fail if they are not up to date instead")
+    parser.add_argument('--foss-only', '--free-only', action='store_true',
+                        help="don't fetch TheMuppets' proprietary vendor dirs, and list what each device will lack")
+    parser.add_argument('--vendor-url', action='append', default=[], metavar='[BRANCH=]TEMPLATE',
+                        help="fetch proprietary vendor dirs from TEMPLATE instead of TheMuppets, e.g. a mirror. "
+                        "{vendor} is replaced by the vendor dir with / replaced by _ (like oneplus_cheeseburger), "
+                        "{branch} by the git branch (like lineage-21). "
+                        "Prefix with BRANCH= to only use it for that branch")
+    parser.add_argument('--vendor-overrides', default=VENDOR_OVERRIDES,
+                        help="vendor overrides file of update_device_metadata.py, whose [devices] are used to find "
+                        "device repos, and to record the vendors of device repos found under another vendor in")
+    parser.add_argument('--interactive', action='store_true',
+                        help="ask which vendor a moved device repo belongs to when several match, instead of "
+                        "skipping the device. The answer is recorded in --vendor-overrides for future runs")
+    parser.add_argument('--answers', metavar='FILE',
+                        help="TOML file with a [vendors] table answering those questions per device, for CI")
+    parser.add_argument('--override', action='append', default=[], type=parse_override, metavar='RELPATH=REF',
+                        help="temporarily fetch RELPATH from REF (a branch name, full ref or commit) instead of the "
+                        "lineageos branch, e.g. to test a kernel branch. Can be given multiple times")
+    parser.add_argument('--shard', type=parse_shard, default=None,
+                        help="only update the i-th of n deterministic partitions of the devices (by their device "
+                        "tree path), given as i/n. Writes <branch>/device-dirs.shard-i-of-n.json and "
+                        "vendor-dirs.shard-i-of-n.json instead, combine them with --merge-shards")
+    parser.add_argument('--merge-shards', action='store_true',
+                        help="merge the outputs of every --shard run for the branch into device-dirs.json and "
+                        "vendor-dirs.json, and record the moved device repos they found, without fetching anything")
     args = parser.parse_args()
+    if args.shard is not None and (args.frozen or args.merge_shards):
+        parser.error('--shard can not be combined with --frozen or --merge-shards')
+    apply_common_args(args)
 
     global debug
     debug = args.debug
 
+    if args.merge_shards:
+        merged = merge_shards(args.branch, args.vendor_overrides)
+        print(f"Merged {' '.join(merged)}. They can be removed now")
+        return
+
     if len(args.product) == 0:
-        metadata = json.load(open('device-metadata.json'))
+        metadata = load('device-metadata.json')
+        devices = resolve_device_names(metadata, args.device, load_device_aliases(args.vendor_overrides))
+        metadata = filter_devices(metadata, devices, args.vendor)
     else:
         metadata = {}
         for product in args.product:
             vendor, device = product.split('_', 1)
             metadata[device] = {'vendor': vendor}
+    if args.shard is not None:
+        metadata = {device: data for device, data in metadata.items()
+                    if in_shard(f"device/{data['vendor']}/{device}", args.shard)}
 
     # Really?
     true_branch = args.branch
@@ -255,23 +670,107 @@ def main() -> None:
     elif args.branch == 'lineage-21.0':
         true_branch = 'lineage-21'
 
-    device_dirs_fn = os.path.join(args.branch, 'device-dirs.json')
-    if os.path.exists(device_dirs_fn):
-        device_dirs = json.load(open(device_dirs_fn))
+    read_known_pins()
+
+    overrides = dict(args.override)
+    if os.path.exists(current_path(args.vendor_overrides)):
+        device_vendors, _ = load_vendor_overrides(args.vendor_overrides)
     else:
-        device_dirs = {}
-    device_dirs_result = fetch_device_dirs(metadata, "https://github.com/LineageOS", true_branch,
-                      device_dirs, lambda dirs: save(device_dirs_fn, dirs))
+        device_vendors = DEVICE_VENDORS
+
+    # A template for this branch takes precedence over one for all branches (stored under '')
+    vendor_url_templates: Dict[str, str] = {}
+    for vendor_url in args.vendor_url:
+        url_branch, sep, template = vendor_url.partition('=')
+        if sep == '' or '/' in url_branch:
+            vendor_url_templates[''] = vendor_url
+        else:
+            vendor_url_templates[url_branch] = template
+    vendor_url_template = vendor_url_templates.get(args.branch, vendor_url_templates.get(''))
 
+    device_dirs_fn = os.path.join(args.branch, 'device-dirs.json')
     vendor_dirs_fn = os.path.join(args.branch, 'vendor-dirs.json')
-    if os.path.exists(vendor_dirs_fn):
-        vendor_dirs = json.load(open(vendor_dirs_fn))
+    # A shard starts off the complete files, to reuse their revisions
+    device_dirs = load(device_dirs_fn, {})
+    vendor_dirs = load(vendor_dirs_fn, {})
+    if args.shard is not None:
+        device_dirs_fn = shard_filename(device_dirs_fn, args.shard)
+        vendor_dirs_fn = shard_filename(vendor_dirs_fn, args.shard)
+        device_dirs = load(device_dirs_fn, device_dirs)
+        vendor_dirs = load(vendor_dirs_fn, vendor_dirs)
+    vendors = {device: data['vendor'] for device, data in metadata.items()}
+    device_dirs_result = fetch_device_dirs(metadata, "https://github.com/LineageOS", true_branch,
+                      device_dirs, None if args.frozen else lambda dirs: save(device_dirs_fn, dirs),
+                      jobs=args.jobs, overrides=overrides, device_vendors=device_vendors,
+                      choose_vendor=make_vendor_chooser(args.answers, args.interactive))
+    # Vendors of moved device repos discovered while fetching
+    moved = sorted(device for device, data in metadata.items() if data['vendor'] != vendors[device])
+    if len(args.product) == 0 and not args.frozen and len(moved) > 0:
+        moved_vendors = {device: metadata[device]['vendor'] for device in moved}
+        if args.shard is not None:
+            # Shards running at the same time would overwrite each other's changes to the shared files,
+            # so --merge-shards records them
+            save(shard_filename(os.path.join(args.branch, 'moved-vendors.json'), args.shard), moved_vendors)
+        else:
+            record_moved_vendors(args.vendor_overrides, moved_vendors)
+
+    repo_dirs_fn = os.path.join(args.branch, 'repo.json')
+    repo_dirs = load(repo_dirs_fn, {})
+    for warning in lint_device_dirs(device_dirs_result, repo_dirs):
+        print(f'WARNING: {warning}')
+
+    if args.foss_only:
+        # Leave vendor-dirs.json as it is, it's only used by builds that include the blobs
+        vendor_dirs_result = vendor_dirs
+        required_vendor = get_required_vendor_dirs(metadata, args.branch, device_dirs_result)
+        print('FOSS-only: not fetching proprietary vendor dirs. Builds will lack:')
+        for device, vendor_relpaths in sorted(required_vendor.items()):
+            print(f"  {device}: {', '.join(f'vendor/{p}' for p in sorted(vendor_relpaths)) or 'nothing'}")
     else:
-        vendor_dirs = {}
-    fetch_vendor_dirs(metadata, "https://github.com/TheMuppets", args.branch, true_branch,
-                      device_dirs_result, vendor_dirs, lambda dirs: save(vendor_dirs_fn, dirs))
+        vendor_dirs_result = fetch_vendor_dirs(metadata, "https://github.com/TheMuppets", args.branch, true_branch,
+                                               device_dirs_result, vendor_dirs,
+                                               None if args.frozen else lambda dirs: save(vendor_dirs_fn, dirs),
+                                               url_template=vendor_url_template, jobs=args.jobs)
+
+    if args.shard is not None:
+        # Only keep what this shard's devices use, so merging doesn't bring back stale entries of other shards
+        shard_relpaths: Set[str] = set()
+        for device, data in metadata.items():
+            shard_relpaths |= relpath_with_deps(device_dirs_result, f"device/{data['vendor']}/{device}")
+        for vendor_relpaths in get_required_vendor_dirs(metadata, args.branch, device_dirs_result).values():
+            shard_relpaths |= {f'vendor/{vendor_relpath}' for vendor_relpath in vendor_relpaths}
+        save(device_dirs_fn, {k: v for k, v in device_dirs_result.items() if k in shard_relpaths})
+        save(vendor_dirs_fn, {k: v for k, v in vendor_dirs_result.items() if k in shard_relpaths})
+
+    # Shards record it when merging, since their device-dirs.json is incomplete
+    if len(args.product) == 0 and args.shard is None:
+        metadata_with_nonfree = with_nonfree(load('device-metadata.json'), args.branch, device_dirs_result)
+        if not args.frozen:
+            save('device-metadata.json', metadata_with_nonfree)
+
+    print(f'{len(changed_relpaths)} dirs changed')
+
+    overridden = sorted(relpath for relpath, dir_info in device_dirs_result.items() if 'overrideRef' in dir_info)
+    for relpath in overridden:
+        print(f"WARNING: {relpath} is pinned to {device_dirs_result[relpath]['overrideRef']} by --override. "
+              'Run again without it before committing')
+
+    if args.frozen:
+        up_to_date = check_up_to_date(device_dirs_fn, device_dirs_result)
+        up_to_date = check_up_to_date(vendor_dirs_fn, vendor_dirs_result) and up_to_date
+        if len(args.product) == 0 and len(moved) > 0:
+            print(f"{args.vendor_overrides} is missing the new vendor of {' '.join(moved)}", file=sys.stderr)
+            up_to_date = False
+        if len(args.product) == 0:
+            up_to_date = check_up_to_date('device-metadata.json', metadata_with_nonfree) and up_to_date
+        if len(overridden) > 0:
+            print(f'{device_dirs_fn} contains temporary overrides: {" ".join(overridden)}', file=sys.stderr)
+            up_to_date = False
+        if not up_to_date:
+            sys.exit(1)
 
 
 if __name__ == '__main__':
     os.chdir(pathlib.Path(__file__).parent.resolve())
-    main()
+    # device-metadata.json, device-dirs.json and vendor-dirs.json only make sense together
+    run_main(main, staged=True)
@@ -2,43 +2,156 @@
 # SPDX-FileCopyrightText: 2020 Daniel Fullmer and robotnix contributors
 # SPDX-License-Identifier: MIT
 
-from typing import Any
+from typing import Any, Dict, List, Sequence, Tuple, cast
+import argparse
 import json
 import urllib.request
 import os
 import pathlib
+import sys
 import tomllib
 
-from robotnix_common import save, get_store_path, checkout_git
+from robotnix_common import save, load, current_path, write_file, get_store_path, checkout_git, check_up_to_date, \
+    add_common_args, apply_common_args, run_main
+
+
+# Lines in lineage-build-targets look like "<device> <variant> <branch> <period>", where period is
+# the build cadence (e.g. "W" for weekly). Upstream occasionally adds columns, so keep any extra
+# fields around instead of failing on them.
+def parse_build_targets(lines: List[str]) -> Dict[str, Dict[str, Any]]:
+    targets: Dict[str, Dict[str, Any]] = {}
+    for line in lines:
+        line = line.strip()
+        if line == "":
+            continue
+        if line.startswith("#"):
+            continue
+
+        device, variant, branch, *extra = line.split()
+
+        target: Dict[str, Any] = {
+            'variant': variant,
+            'branch': branch,
+        }
+        if len(extra) > 0:
+            target['period'] = extra[0]
+        if len(extra) > 1:
+            target['extra_fields'] = extra[1:]
+        targets[device] = target
+
+    return targets
+
+
+def load_supported_devices() -> Any:
+    supported_devices_toml = os.path.join(os.path.dirname(__file__), 'supported_devices.toml')
+    return tomllib.loads(open(supported_devices_toml).read())
+
+
+# Device repos are named android_device_<vendor>_<device>, but the vendor there doesn't always
+# match the (lowercased) OEM from devices.json. These are applied per device first, then per OEM.
+DEVICE_VENDORS = {
+    # shamu needs a workaround as well
+    'shamu' : 'moto',
+    # Workaround google device names source tree inconsistency
+    'flox' : 'asus',
+    # wade is Google but uses askey vendor dirs? Dynalink is definitely wrong though.
+    'wade' : 'askey',
+    'deadpool' : 'askey',
+    # Nexus 7 (2013) LTE, made by Asus
+    'debx' : 'asus',
+    # 10.or is apparently a vendor name. Why TF do you have to put dots in your name.
+    # TODO check whether we can exclude this case by always fetching from vendor_device for LOS-20 devices
+    'G' : '10or'
+}
+OEM_VENDORS = {
+    # Workaround name inconsistency with LG
+    'lg': 'lge',
+    # Look how cool my name is mom, parenthesis!
+    'f(x)tec': 'fxtec',
+    # Urgh
+    '10.or': '10or',
+    # Really?
+    'banana pi': 'bananapi',
+}
+
+
+# Additional vendor overrides, read by default if it exists. update_device_dirs.py adds devices to it
+# whose repo it found under another vendor
+VENDOR_OVERRIDES = 'vendor_overrides.toml'
+
+
+# Read additional [devices] and [oems] vendor overrides from a TOML file, on top of the built-in ones
+def load_vendor_overrides(filename: str) -> Tuple[Dict[str, str], Dict[str, str]]:
+    overrides = tomllib.loads(open(current_path(filename)).read())
+    return {**DEVICE_VENDORS, **overrides.get('devices', {})}, {**OEM_VENDORS, **overrides.get('oems', {})}
+
+
+# Add a [devices] entry to a vendor overrides file. tomllib can't write TOML, but the file only holds
+# two tables of strings, so it is simply written out again (JSON strings are valid TOML strings)
+def add_device_vendor_override(filename: str, device: str, vendor: str) -> None:
+    path = current_path(filename)
+    overrides = tomllib.loads(open(path).read()) if os.path.exists(path) else {}
+    overrides.setdefault('devices', {})[device] = vendor
+    lines = [
+        '# Vendor overrides for update_device_metadata.py, in addition to the built-in ones.',
+        '# [devices] maps device codenames and [oems] (lowercased) OEM names to the vendor used in repo names.',
+        '# update_device_dirs.py adds devices whose repo it found under another vendor.',
+        '# [aliases] maps alternate codenames to the one in device-metadata.json, for --device.',
+    ]
+    for table in ['devices', 'oems', 'aliases']:
+        lines += ['', f'[{table}]']
+        lines += [f'{json.dumps(k)} = {json.dumps(v)}' for k, v in sorted(overrides.get(table, {}).items())]
+    write_file(filename, '\n'.join(lines) + '\n')
+
+
+# Alternate codenames which --device accepts, from the [aliases] table of a vendor overrides file
+def load_device_aliases(filename: str) -> Dict[str, str]:
+    path = current_path(filename)
+    if not os.path.exists(path):
+        return {}
+    return cast(Dict[str, str], tomllib.loads(open(path).read()).get('aliases', {}))
+
+
+# Map the names given with --device to codenames. Besides codenames, these can be aliases and
+# marketing names like "OnePlus 5" or "Fairphone 4", both matched case-insensitively. Names which
+# match nothing are kept as they are, so they are reported as unknown devices
+def resolve_device_names(metadata: Any, names: List[str], aliases: Dict[str, str]) -> List[str]:
+    devices = []
+    for name in names:
+        name = aliases.get(name, name)
+        if name in metadata:
+            devices.append(name)
+            continue
+        matches = sorted(
+            device for device, data in metadata.items()
+            if name.casefold() in [device.casefold(), data.get('name', '').casefold(),
+                                   f"{data.get('oem', '')} {data.get('name', '')}".casefold()]
+        )
+        if len(matches) > 0:
+            print(f"NOTE: taking {name} to mean {' '.join(matches)}", file=sys.stderr)
+        devices += matches or [name]
+    return devices
 
 
 def fetch_metadata(
         hudson_url: str = 'https://github.com/LineageOS/hudson',
         lineage_build_targets_path: str = 'lineage-build-targets',
-        devices_json_path: str = 'updater/devices.json'
+        devices_json_path: str = 'updater/devices.json',
+        device_vendors: Dict[str, str] = DEVICE_VENDORS,
+        oem_vendors: Dict[str, str] = OEM_VENDORS,
+        unlisted_devices: Sequence[str] = (),
         ) -> Any:
     metadata = {}
 
     hudson_path = get_store_path(checkout_git(hudson_url, 'refs/heads/main')['path'])
 
-    supported_devices_toml = os.path.join(os.path.dirname(__file__), 'supported_devices.toml')
-    supported_devices = tomllib.loads(open(supported_devices_toml).read())
+    supported_devices = load_supported_devices()
 
     lineage_build_targets = open(f'{hudson_path}/{lineage_build_targets_path}').readlines()
-    for line in lineage_build_targets:
-        line = line.strip()
-        if line == "":
-            continue
-        if line.startswith("#"):
-            continue
-
-        device, variant, branch, updatePeriod = line.split()
-
-        if device in supported_devices['supported'] and not device in supported_devices['unsupported']:
-            metadata[device] = {
-                'variant': variant,
-                'branch': branch,
-            }
+    for device, target in parse_build_targets(lineage_build_targets).items():
+        if device in unlisted_devices or \
+                device in supported_devices['supported'] and not device in supported_devices['unsupported']:
+            metadata[device] = target
 
     ###
 
@@ -47,44 +160,119 @@ def fetch_metadata(
         if data['model'] not in metadata:
             continue
 
-        workaround_map = {
-            # shamu needs a workaround as well
-            'shamu' : 'moto',
-            # Workaround google device names source tree inconsistency
-            'flox' : 'asus',
-            # wade is Google but uses askey vendor dirs? Dynalink is definitely wrong though.
-            'wade' : 'askey',
-            'deadpool' : 'askey',
-            # 10.or is apparently a vendor name. Why TF do you have to put dots in your name.
-            # TODO check whether we can exclude this case by always fetching from vendor_device for LOS-20 devices
-            'G' : '10or'
-        }
         device = data['model']
-        vendor = workaround_map[device] if device in workaround_map else data['oem'].lower()
-
-        # Workaround name inconsistency with LG
-        if vendor == 'lg':
-            vendor = 'lge'
-        # Look how cool my name is mom, parenthesis!
-        if vendor == 'f(x)tec':
-            vendor = 'fxtec'
-        # Urgh
-        if vendor == '10.or':
-            vendor = '10or'
-        # Really?
-        if vendor == 'banana pi':
-            vendor = 'bananapi'
+        oem = data['oem'].lower()
+        vendor = device_vendors.get(device, oem_vendors.get(oem, oem))
 
+        # vendor is the code used in repo names and relpaths, oem and name are for display
         metadata[data['model']].update({
             'vendor': vendor,
+            'oem': data['oem'],
             'name': data['name'],
             'lineage_recovery': data.get('lineage_recovery', False)
         })
 
+    # Upstream sometimes adds a build target before the device's devices.json entry. Without a
+    # vendor we can't find its sources, so leave it out rather than record a broken entry.
+    missing = sorted(device for device, data in metadata.items() if 'vendor' not in data)
+    for device in missing:
+        print(f"WARNING: {device} is in {lineage_build_targets_path} but missing from {devices_json_path}, "
+              "skipping it", file=sys.stderr)
+        del metadata[device]
+
+    return metadata
+
+
+# Devices given by name, plus all devices of the given vendors, limited to those on the given
+# branches. Filters which are left empty don't restrict anything
+def filter_devices(metadata: Any, devices: List[str], vendors: List[str], branches: Sequence[str] = ()) -> Any:
+    if len(devices) > 0 or len(vendors) > 0:
+        metadata = {device: data for device, data in metadata.items() if device in devices or data['vendor'] in vendors}
+    if len(branches) > 0:
+        metadata = {device: data for device, data in metadata.items() if data['branch'] in branches}
     return metadata
 
 
+# Replace the entries selected by the filters in the existing metadata, leaving the others alone.
+# Selected devices which are gone upstream are dropped, except with a branch filter: there they
+# may just have moved to another branch, which a later run for that branch will pick up.
+def merge_filtered(existing: Any, metadata: Any, devices: List[str], vendors: List[str], branches: List[str]) -> Any:
+    selected = filter_devices(existing, devices, vendors) if len(branches) == 0 else {}
+    return {**{device: data for device, data in existing.items() if device not in selected}, **metadata}
+
+
+def eol_devices(metadata: Any, eol_branches: List[str]) -> List[str]:
+    return sorted(device for device, data in metadata.items() if data['branch'] in eol_branches)
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser()
+    parser.add_argument('--frozen', action='store_true',
+                        help="don't write device-metadata.json, fail if it is not up to date instead")
+    parser.add_argument('--fail-on-eol', action='store_true',
+                        help="fail if any device is on a branch which is end-of-life upstream")
+    parser.add_argument('--vendor-overrides', default=VENDOR_OVERRIDES,
+                        help="TOML file with [devices] and [oems] tables mapping device and OEM names to the vendor "
+                        "used in repo names, in addition to the built-in overrides. Only read if it exists")
+    parser.add_argument('--device', action='append', default=[],
+                        help="only update this device, keeping the others as they are. Also takes alternate "
+                        "codenames from the [aliases] of --vendor-overrides and marketing names like "
+                        "'OnePlus 5'. Can be given multiple times")
+    parser.add_argument('--vendor', action='append', default=[],
+                        help="only update devices of this vendor, keeping the others as they are. "
+                        "Can be given multiple times")
+    parser.add_argument('--branch', action='append', default=[],
+                        help="only update devices whose build target is on this branch (e.g. lineage-22.1), "
+                        "keeping the others as they are. Can be given multiple times")
+    parser.add_argument('--allow-unlisted', action='store_true',
+                        help="also keep the devices given with --device which supported_devices.toml doesn't list "
+                        "as supported, like when trying out a new device")
+    add_common_args(parser)
+    args = parser.parse_args()
+    apply_common_args(args)
+
+    args.device = resolve_device_names({}, args.device, load_device_aliases(args.vendor_overrides))
+    unlisted_devices = args.device if args.allow_unlisted else []
+    if os.path.exists(current_path(args.vendor_overrides)):
+        device_vendors, oem_vendors = load_vendor_overrides(args.vendor_overrides)
+        metadata = fetch_metadata(device_vendors=device_vendors, oem_vendors=oem_vendors,
+                                  unlisted_devices=unlisted_devices)
+    else:
+        metadata = fetch_metadata(unlisted_devices=unlisted_devices)
+
+    args.device = resolve_device_names(metadata, args.device, {})
+    metadata = filter_devices(metadata, args.device, args.vendor, args.branch)
+    supported_devices = load_supported_devices()
+    for device in args.device:
+        if device not in metadata:
+            print(f"WARNING: {device} is not a supported build target", file=sys.stderr)
+        elif device not in supported_devices['supported'] or device in supported_devices['unsupported']:
+            print(f"NOTE: {device} isn't listed as supported in supported_devices.toml. Add it there, "
+                  "or update.sh will drop it from device-metadata.json again", file=sys.stderr)
+
+    eol = eol_devices(metadata, supported_devices.get('eol_branches', []))
+    for device in eol:
+        print(f"WARNING: {device} is on {metadata[device]['branch']}, which is end-of-life", file=sys.stderr)
+    if args.fail_on_eol and len(eol) > 0:
+        sys.exit(1)
+
+    # Which proprietary vendor dirs a device needs on its branch is only known to update_device_dirs.py
+    existing = load('device-metadata.json', {})
+    for device, data in metadata.items():
+        if 'nonfree' in existing.get(device, {}) and existing[device]['branch'] == data['branch']:
+            data['nonfree'] = existing[device]['nonfree']
+
+    if len(args.device) > 0 or len(args.vendor) > 0 or len(args.branch) > 0:
+        metadata = merge_filtered(load('device-metadata.json'), metadata,
+                                  args.device, args.vendor, args.branch)
+
+    if args.frozen:
+        if not check_up_to_date('device-metadata.json', metadata):
+            sys.exit(1)
+    else:
+        save('device-metadata.json', metadata)
+
+
 if __name__ == '__main__':
-    metadata = fetch_metadata()
     os.chdir(pathlib.Path(__file__).parent.resolve())
-    save('device-metadata.json', metadata)
+    run_main(main)
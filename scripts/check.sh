@@ -9,4 +9,5 @@ cd "$(dirname "${BASH_SOURCE[0]}")/.."
 mypy --exclude apks/chromium .
 flake8 --exclude apks/chromium .
 pytest .
+flavors/lineageos/check_consistency.py
 shellcheck ./*.sh flavors/**/*.sh modules/pixel/update.sh scripts/patchelf-prefix.sh pkgs/robotnix/unpack-images.sh
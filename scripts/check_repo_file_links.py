@@ -0,0 +1,47 @@
+#!/usr/bin/env python3
+# SPDX-FileCopyrightText: 2026 robotnix contributors
+# SPDX-License-Identifier: MIT
+
+from typing import Any, Dict, List
+
+import argparse
+import glob
+import json
+import os
+import sys
+
+from robotnix_common import checkout_git, get_mirrored_url, get_store_path
+
+
+def check_links(data: Dict[str, Any]) -> List[str]:
+    """Check that the src of every copyfile/linkfile exists in the pinned source of its project"""
+    errors: List[str] = []
+    for relpath, p in sorted(data.items()):
+        files = p.get('copyfiles', []) + p.get('linkfiles', [])
+        if len(files) == 0:
+            continue
+
+        git_info = checkout_git(get_mirrored_url(p['url']), p['rev'], p.get('fetchSubmodules', False), False)
+        path = get_store_path(git_info['path'])
+        for f in files:
+            # repo allows globs in linkfile sources
+            if len(glob.glob(os.path.join(path, f['src']))) == 0:
+                errors.append(f"{relpath}: {f['src']} (for {f['dest']}) does not exist at {p['rev']}")
+    return errors
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser(
+        description="verify that copyfile/linkfile sources in a repo json file exist in the fetched sources")
+    parser.add_argument('file', help="repo json file to check")
+    args = parser.parse_args()
+
+    errors = check_links(json.load(open(args.file)))
+    for error in errors:
+        print(f'ERROR: {error}', file=sys.stderr)
+    if len(errors) > 0:
+        sys.exit(1)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,21 @@
+#!/usr/bin/env python3
+# SPDX-FileCopyrightText: 2026 robotnix contributors
+# SPDX-License-Identifier: MIT
+
+import argparse
+
+from robotnix_common import commit_staged
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser(
+        description="move the output files an updater run staged under ROBOTNIX_STAGING_DIR into place")
+    parser.add_argument('staging_dir', help="staging directory, removed afterwards")
+    args = parser.parse_args()
+
+    for filename in commit_staged(args.staging_dir):
+        print(f"Updated {filename}")
+
+
+if __name__ == "__main__":
+    main()
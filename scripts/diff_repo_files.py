@@ -0,0 +1,46 @@
+#!/usr/bin/env python3
+# SPDX-FileCopyrightText: 2026 robotnix contributors
+# SPDX-License-Identifier: MIT
+
+from typing import List
+
+import argparse
+import json
+
+from robotnix_common import EntryChange, compare_entries, diff_entries
+
+
+def format_markdown(changes: List[EntryChange]) -> str:
+    lines = [
+        '| | Project | Old rev | New rev |',
+        '|---|---|---|---|',
+    ]
+    for c in changes:
+        old_rev = c['old_rev'][:12] if c['old_rev'] is not None else ''
+        new_rev = c['new_rev'][:12] if c['new_rev'] is not None else ''
+        lines.append(f"| {c['change']} | `{c['key']}` | {old_rev} | {new_rev} |")
+    return '\n'.join(lines)
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser(
+        description="show added, removed and changed entries between two repo json (or device metadata) files")
+    parser.add_argument('--format', choices=['text', 'json', 'markdown'], default='text', help="output format")
+    parser.add_argument('old', help="old version of the file")
+    parser.add_argument('new', help="new version of the file")
+    args = parser.parse_args()
+
+    old = json.load(open(args.old))
+    new = json.load(open(args.new))
+
+    if args.format == 'json':
+        print(json.dumps(compare_entries(old, new), indent=2))
+    elif args.format == 'markdown':
+        print(format_markdown(compare_entries(old, new)))
+    else:
+        for line in diff_entries(old, new):
+            print(line)
+
+
+if __name__ == "__main__":
+    main()
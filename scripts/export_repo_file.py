@@ -0,0 +1,99 @@
+#!/usr/bin/env python3
+# SPDX-FileCopyrightText: 2026 robotnix contributors
+# SPDX-License-Identifier: MIT
+
+from typing import Any, Dict, List
+
+import argparse
+import json
+import os
+import sys
+import urllib.parse
+
+from robotnix_common import write_file
+
+
+def nix_str(s: str) -> str:
+    return '"' + s.replace('\\', '\\\\').replace('"', '\\"').replace('${', '\\${') + '"'
+
+
+def nix_bool(b: bool) -> str:
+    return 'true' if b else 'false'
+
+
+def fetch_rev(p: Dict[str, Any]) -> str:
+    # Same as modules/source.nix: use the tag if there is one, so it ends up in the derivation name
+    if p.get('revisionExpr', '').startswith('refs/tags/'):
+        return str(p['revisionExpr'])
+    return str(p['rev'])
+
+
+def format_nix(data: Dict[str, Any], source: str) -> str:
+    """An attrset of fetchgit calls, one for each entry, fetched the same way modules/source.nix does"""
+    lines: List[str] = [
+        f'# Generated by export_repo_file.py from {source}',
+        '{ fetchgit }:',
+        '{',
+    ]
+    for relpath, p in sorted(data.items()):
+        lines += [
+            f'  {nix_str(relpath)} = fetchgit {{',
+            f"    url = {nix_str(p['url'])};",
+            f'    rev = {nix_str(fetch_rev(p))};',
+            f"    sha256 = {nix_str(p['sha256'])};",
+            f"    fetchSubmodules = {nix_bool(p.get('fetchSubmodules', False))};",
+            f"    fetchLFS = {nix_bool(p.get('fetchLFS', True))};",
+            '    deepClone = false;',
+            '  };',
+        ]
+    lines.append('}')
+    return '\n'.join(lines)
+
+
+def format_flake(data: Dict[str, Any], source: str) -> str:
+    """Flake inputs for each entry, to be pasted into the inputs of a flake.nix"""
+    lines: List[str] = [f'# Generated by export_repo_file.py from {source}']
+    for relpath, p in sorted(data.items()):
+        query = {'rev': p['rev']}
+        if p.get('fetchSubmodules', False):
+            query['submodules'] = '1'
+        if p.get('fetchLFS', True):
+            query['lfs'] = '1'
+        url = f"git+{p['url']}?{urllib.parse.urlencode(query)}"
+        lines.append(f'{nix_str(relpath)} = {{ url = {nix_str(url)}; flake = false; }};')
+    return '\n'.join(lines)
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser(
+        description="export a repo json file (or LineageOS device/vendor dirs) as Nix, for use without IFD")
+    parser.add_argument('--format', choices=['nix', 'flake'], default='nix',
+                        help="nix: a function taking fetchgit and returning an attrset of sources, "
+                        "flake: flake inputs for each source")
+    parser.add_argument('--out', default=None, help="path to output file, defaults to stdout")
+    parser.add_argument('file', help="repo json file to export")
+    args = parser.parse_args()
+
+    data = json.load(open(args.file))
+    source = os.path.normpath(args.file)
+
+    # Projects left out with --exclude-path, --include-prefix, --groups, --shard etc. stay in the file unfetched
+    unfetched = sorted(relpath for relpath, p in data.items() if 'sha256' not in p)
+    if len(unfetched) > 0:
+        print(f"WARNING: skipping {len(unfetched)} projects which were not fetched: {' '.join(unfetched)}",
+              file=sys.stderr)
+        data = {relpath: p for relpath, p in data.items() if 'sha256' in p}
+
+    if args.format == 'flake':
+        text = format_flake(data, source)
+    else:
+        text = format_nix(data, source)
+
+    if args.out is not None:
+        write_file(args.out, text + '\n')
+    else:
+        print(text)
+
+
+if __name__ == "__main__":
+    main()
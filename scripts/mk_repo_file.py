@@ -14,10 +14,14 @@ import os
 import re
 import shutil
 import subprocess
+import sys
 import tempfile
+import tomllib
 from datetime import datetime
 
-from robotnix_common import save, checkout_git, ls_remote, get_mirrored_url, check_free_space
+from robotnix_common import save, load, current_path, checkout_git, resolve_ref, get_mirrored_url, \
+    check_free_space, check_up_to_date, add_common_args, apply_common_args, run_main, require_network, uses_lfs, \
+    get_store_path, in_shard, parse_shard
 
 REPO_FLAGS = [
     "--quiet",
@@ -41,6 +45,7 @@ class ProjectInfoDict(TypedDict, total=False):
     tree: str
     sha256: str
     fetchSubmodules: bool
+    fetchLFS: bool
     groups: List[str]
     copyfiles: List[Dict[str, str]]
     linkfiles: List[Dict[str, str]]
@@ -50,6 +55,7 @@ class CachedInfo(TypedDict, total=False):
     sha256: str
     dateTime: int
     tree: str
+    fetchLFS: bool  # Only if the hash includes LFS files although fetching them is disabled in general
 
 
 revInfo: Dict[Tuple[str, bool], CachedInfo] = {}  # (rev, fetch_submodules) -> CachedInfo
@@ -65,11 +71,27 @@ def add_to_cache(p: ProjectInfoDict) -> None:
     cached_info.update({'sha256': p['sha256']})
     if 'dateTime' in p:
         cached_info.update({'dateTime': p['dateTime']})
+    if p.get('fetchLFS', False):
+        cached_info.update({'fetchLFS': True})
     if 'tree' in p:
         cached_info.update({'tree': p['tree']})
         treeInfo[p['tree'], p.get('fetchSubmodules', False)] = cast(CachedInfo, dict(cached_info))
 
 
+def in_groups(groups: List[str], include_groups: List[str], exclude_groups: List[str]) -> bool:
+    # Same as repo sync -g: every project is in 'all', and in 'default' unless it is in 'notdefault'
+    implicit = {'all'} if 'notdefault' in groups else {'all', 'default'}
+    project_groups = set(groups) | implicit
+    if len(include_groups) > 0 and project_groups.isdisjoint(include_groups):
+        return False
+    return project_groups.isdisjoint(exclude_groups)
+
+
+def load_excludes(filename: str) -> Dict[str, str]:
+    """Read a TOML file with an [exclude] table mapping project paths to the reason they are never fetched"""
+    return cast(Dict[str, str], tomllib.loads(open(filename).read()).get('exclude', {}))
+
+
 def make_repo_file(url: str, ref: str,
                    ref_type: ManifestRefType = ManifestRefType.TAG,
                    prev_data: Optional[Dict[str, ProjectInfoDict]] = None,
@@ -81,6 +103,10 @@ def make_repo_file(url: str, ref: str,
                    callback: Optional[Callable[[Any], Any]] = None,
                    jobs: int = 1,
                    fetch_lfs: bool = True,
+                   shard: Optional[Tuple[int, int]] = None,
+                   include_groups: Optional[List[str]] = None,
+                   exclude_groups: Optional[List[str]] = None,
+                   lfs_projects: Optional[List[str]] = None,
                    ) -> Dict[str, ProjectInfoDict]:
     if local_manifests is None:
         local_manifests = []
@@ -92,6 +118,12 @@ def make_repo_file(url: str, ref: str,
         include_prefix = []
     if exclude_path is None:
         exclude_path = []
+    if include_groups is None:
+        include_groups = []
+    if exclude_groups is None:
+        exclude_groups = []
+    if lfs_projects is None:
+        lfs_projects = []
 
     data: Dict[str, ProjectInfoDict]
 
@@ -101,6 +133,7 @@ def make_repo_file(url: str, ref: str,
         data = {}
 
         print("Fetching information for %s %s" % (url, ref))
+        require_network(url)
         with tempfile.TemporaryDirectory() as tmpdir:
             subprocess.check_call([
                 'repo', 'init', f'--manifest-url={url}', f'--manifest-branch=refs/{ref_type.value}/{ref}', *REPO_FLAGS
@@ -120,7 +153,6 @@ def make_repo_file(url: str, ref: str,
             if callback is not None:
                 callback(data)
 
-    pool = multiprocessing.pool.ThreadPool(jobs)
     cb_lock = multiprocessing.Lock()
 
     def process_item(item: Tuple[str, ProjectInfoDict]) -> None:
@@ -128,6 +160,9 @@ def make_repo_file(url: str, ref: str,
         assert project_fetch_submodules is not None
         assert include_prefix is not None
         assert exclude_path is not None
+        assert include_groups is not None
+        assert exclude_groups is not None
+        assert lfs_projects is not None
 
         relpath, p = item
 
@@ -137,6 +172,12 @@ def make_repo_file(url: str, ref: str,
         if relpath in exclude_path:
             return
 
+        if shard is not None and not in_shard(relpath, shard):
+            return
+
+        if not in_groups(p.get('groups', []), include_groups, exclude_groups):
+            return
+
         for project, rev in override_project_revs.items():
             # We have to iterate over the whole output since we don't save
             # the project name anymore, just the relpath, which isn't
@@ -154,16 +195,10 @@ def make_repo_file(url: str, ref: str,
                 p['rev'] = p['revisionExpr']
             else:
                 # Otherwise, fetch this information from the git remote
-                remote_revs = ls_remote(p['url'])
-                if p['revisionExpr'] in remote_revs:
-                    resolved_rev = p['revisionExpr']
-                elif ('refs/tags/' + p['revisionExpr']) in remote_revs:
-                    resolved_rev = 'refs/tags/' + p['revisionExpr']
-                elif ('refs/heads/' + p['revisionExpr']) in remote_revs:
-                    resolved_rev = 'refs/heads/' + p['revisionExpr']
-                else:
+                resolved_rev = resolve_ref(p['url'], p['revisionExpr'])
+                if resolved_rev is None:
                     raise Exception(f"{p['url']} is missing {p['revisionExpr']}")
-                p['rev'] = remote_revs[resolved_rev]
+                p['rev'] = resolved_rev
 
         # TODO: Incorporate "sync-s" setting from upstream manifest if it exists
         fetch_submodules = relpath in project_fetch_submodules
@@ -171,11 +206,19 @@ def make_repo_file(url: str, ref: str,
         if fetch_submodules:
             p['fetchSubmodules'] = True
 
+        # The hash depends on whether LFS files were fetched, so only use cached ones which agree
+        def use_cached(cached: CachedInfo) -> bool:
+            if not fetch_lfs and relpath in lfs_projects and not cached.get('fetchLFS', False):
+                return False
+            p.update(cast(ProjectInfoDict, {k: v for k, v in cached.items() if k != 'fetchLFS'}))
+            if cached.get('fetchLFS', False) and not fetch_lfs:
+                p['fetchLFS'] = True
+            return True
+
         if 'sha256' not in p:
             print("Fetching information for %s %s" % (p['url'], p['rev']))
             # Used cached copies if available
-            if (p['rev'], fetch_submodules) in revInfo:
-                p.update(cast(ProjectInfoDict, revInfo.get((p['rev'], fetch_submodules), {})))
+            if (p['rev'], fetch_submodules) in revInfo and use_cached(revInfo[p['rev'], fetch_submodules]):
                 return
 
             p_url = get_mirrored_url(p['url'])
@@ -186,17 +229,27 @@ def make_repo_file(url: str, ref: str,
                     ['git', 'log', '-1', '--pretty=%T', p['rev']],
                     cwd=p_url+'.git').decode().strip()
                 if (p['tree'], fetch_submodules) in treeInfo:
-                    p.update(cast(ProjectInfoDict, treeInfo.get((p['tree'], fetch_submodules), {})))
-                    found_treehash = True
+                    found_treehash = use_cached(treeInfo[p['tree'], fetch_submodules])
             if found_treehash:
                 return
 
             # Fetch information. Use revisionExpr if it is a tag so we use the
-            # tag in the name of the nix derivation instead of the revision
-            if p['revisionExpr'].startswith('refs/tags/'):
-                git_info = checkout_git(p_url, p['revisionExpr'], fetch_submodules, fetch_lfs)
+            # tag in the name of the nix derivation instead of the revision.
+            # Gerrit change refs have to be fetched by ref as well, since their
+            # commits usually aren't reachable from any branch.
+            if p['revisionExpr'].startswith('refs/tags/') or p['revisionExpr'].startswith('refs/changes/'):
+                checkout_rev = p['revisionExpr']
             else:
-                git_info = checkout_git(p_url, p['rev'], fetch_submodules, fetch_lfs)
+                checkout_rev = p['rev']
+            project_fetch_lfs = fetch_lfs or relpath in lfs_projects
+            git_info = checkout_git(p_url, checkout_rev, fetch_submodules, project_fetch_lfs)
+            if not project_fetch_lfs and uses_lfs(get_store_path(git_info['path'])):
+                # Without LFS we'd pin the pointer files, which isn't what the Nix side fetches
+                print(f"{relpath} uses Git LFS, fetching it again with LFS")
+                project_fetch_lfs = True
+                git_info = checkout_git(p_url, checkout_rev, fetch_submodules, project_fetch_lfs)
+            if project_fetch_lfs and not fetch_lfs:
+                p['fetchLFS'] = True
 
             p['dateTime'] = int(datetime.fromisoformat(git_info['date']).timestamp())
             p['sha256'] = git_info['sha256']
@@ -207,7 +260,15 @@ def make_repo_file(url: str, ref: str,
                 if callback is not None:
                     callback(data)
 
-    pool.map(process_item, data.items())
+    with multiprocessing.pool.ThreadPool(jobs) as pool:
+        pool.map(process_item, data.items())
+
+    gerrit_changes = sorted(relpath for relpath, p in data.items()
+                            if p.get('revisionExpr', '').startswith('refs/changes/'))
+    if len(gerrit_changes) > 0:
+        print("WARNING: The following projects are pinned to unmerged Gerrit changes, not a release:")
+        for relpath in gerrit_changes:
+            print(f"  {relpath}: {data[relpath]['revisionExpr']}")
 
     # Save at the end as well!
     if callback is not None:
@@ -216,10 +277,16 @@ def make_repo_file(url: str, ref: str,
     return data
 
 
+# Names of the files read by --cache-search-path
+def is_repo_json(filename: str) -> bool:
+    filename = os.path.basename(filename)
+    return filename.startswith('repo-') and filename.endswith('.json') or filename == 'repo.json'
+
+
 def read_cached_repo_json(path: str) -> None:
     for root, dirs, files in os.walk(path):
         for filename in files:
-            if filename.startswith('repo-') and filename.endswith('.json') or filename == 'repo.json':
+            if is_repo_json(filename):
                 filepath = os.path.join(root, filename)
                 print(f"Loading cached sha256s from {filepath}")
                 data = json.load(open(filepath))
@@ -236,22 +303,41 @@ def main() -> None:
     parser.add_argument('--ref-type', help="the kind of ref that is to be fetched",
                         choices=[t.name.lower() for t in ManifestRefType], default=ManifestRefType.TAG.name.lower())
     parser.add_argument('--resume', help="resume a previous download", action='store_true')
+    parser.add_argument('--frozen', action='store_true',
+                        help="don't write the output file, fail if it is not up to date instead")
     parser.add_argument('--local-manifest', help="path or URL to a .xml file to include in local_manifests",
                         action='append')
     parser.add_argument('--cache-search-path', nargs='*', default=[],
                         help="path to search for any existing repo json files to use for cached sha256s")
     parser.add_argument('--repo-prop', help="repo.prop file to use as source for project git revisions")
     parser.add_argument('--override-tag', help="tag to fetch for subrepos, ignoring revisions from manifest")
-    parser.add_argument('--disable-lfs', action="store_true", help="disables Git LFS support")
+    parser.add_argument('--disable-lfs', action="store_true",
+                        help="disables Git LFS support, except for projects found to use it and --lfs-project")
+    parser.add_argument('--lfs-project', action="append", default=[],
+                        help="fetch Git LFS files for the specified project path, even with --disable-lfs")
     parser.add_argument('--project-fetch-submodules', action="append", default=[],
                         help="fetch submodules for the specified project path")
     parser.add_argument('--include-prefix', action="append", default=[],
                         help="only include paths if they start with the specified prefix")
+    parser.add_argument('--groups', action="append", default=[],
+                        help="only include projects in one of these manifest groups, like repo sync -g. "
+                        "Can be given multiple times or comma-separated")
+    parser.add_argument('--exclude-groups', action="append", default=[],
+                        help="exclude projects in any of these manifest groups (e.g. darwin,mips). "
+                        "Can be given multiple times or comma-separated")
     parser.add_argument('--exclude-path', action="append", default=[], help="paths to exclude from fetching")
+    parser.add_argument('--excludes', action="append", default=[],
+                        help="TOML file with an [exclude] table of paths to exclude from fetching, and why")
     parser.add_argument('--jobs', '-j', default=multiprocessing.cpu_count(), type=int, help="number of concurrent jobs")
+    add_common_args(parser)
+    parser.add_argument('--shard', type=parse_shard, default=None,
+                        help="only fetch the i-th of n deterministic partitions of the projects, given as i/n. "
+                        "Merge the results by running once more with --cache-search-path pointing at the shard "
+                        "outputs, which therefore have to be named repo-*.json (like repo-shard-0.json)")
     parser.add_argument('url', help="manifest URL")
     parser.add_argument('ref', help="manifest ref")
     args = parser.parse_args()
+    apply_common_args(args)
 
     ref_type = ManifestRefType[args.ref_type.upper()]
 
@@ -268,28 +354,55 @@ def main() -> None:
     for path in args.cache_search_path:
         read_cached_repo_json(path)
 
+    excludes = {path: "excluded with --exclude-path" for path in args.exclude_path}
+    for excludes_filename in args.excludes:
+        excludes.update(load_excludes(excludes_filename))
+
     if args.out is not None:
         filename = args.out
     else:
         filename = f'repo-{args.ref}.json'
 
-    if args.resume and os.path.exists(filename):
-        prev_data = json.load(open(filename))
+    if args.shard is not None and not is_repo_json(filename):
+        parser.error(f"--cache-search-path only reads files named repo.json or repo-*.json, so {filename} "
+                     "could not be merged with the other shards")
+
+    if args.frozen and os.path.exists(current_path(filename)):
+        # Reuse the hashes we already have so only changed projects get prefetched
+        for p in load(filename).values():
+            if 'sha256' in p:
+                add_to_cache(p)
+
+    if args.resume and not args.frozen and os.path.exists(current_path(filename)):
+        prev_data = load(filename)
     else:
         prev_data = None
 
-    make_repo_file(args.url, args.ref, ref_type, prev_data,
-                   local_manifests=args.local_manifest,
-                   override_project_revs=override_project_revs,
-                   project_fetch_submodules=args.project_fetch_submodules,
-                   override_tag=args.override_tag,
-                   include_prefix=args.include_prefix,
-                   exclude_path=args.exclude_path,
-                   callback=lambda dirs: save(filename, dirs),
-                   jobs=args.jobs,
-                   fetch_lfs=not args.disable_lfs,
-                   )
+    data = make_repo_file(args.url, args.ref, ref_type, prev_data,
+                          local_manifests=args.local_manifest,
+                          override_project_revs=override_project_revs,
+                          project_fetch_submodules=args.project_fetch_submodules,
+                          override_tag=args.override_tag,
+                          include_prefix=args.include_prefix,
+                          exclude_path=list(excludes.keys()),
+                          callback=None if args.frozen else lambda dirs: save(filename, dirs),
+                          jobs=args.jobs,
+                          fetch_lfs=not args.disable_lfs,
+                          shard=args.shard,
+                          include_groups=[g for groups in args.groups for g in groups.split(',')],
+                          exclude_groups=[g for groups in args.exclude_groups for g in groups.split(',')],
+                          lfs_projects=args.lfs_project,
+                          )
+
+    excluded = sorted(relpath for relpath in excludes.keys() if relpath in data)
+    if len(excluded) > 0:
+        print("The following projects were not fetched:")
+        for relpath in excluded:
+            print(f"  {relpath}: {excludes[relpath]}")
+
+    if args.frozen and not check_up_to_date(filename, data):
+        sys.exit(1)
 
 
 if __name__ == "__main__":
-    main()
+    run_main(main)
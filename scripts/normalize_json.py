@@ -0,0 +1,23 @@
+#!/usr/bin/env python3
+# SPDX-FileCopyrightText: 2026 robotnix contributors
+# SPDX-License-Identifier: MIT
+
+import argparse
+import json
+
+from robotnix_common import save
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser(
+        description="rewrite repo json and device metadata files in the canonical format written by the updaters "
+                    "(useful after hand-edits or merges)")
+    parser.add_argument('files', nargs='+', help="json files to rewrite in place")
+    args = parser.parse_args()
+
+    for filename in args.files:
+        save(filename, json.load(open(filename)))
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,71 @@
+#!/usr/bin/env python3
+# SPDX-FileCopyrightText: 2026 robotnix contributors
+# SPDX-License-Identifier: MIT
+
+from typing import Any, Dict, List, Tuple, TypedDict, Union
+
+import argparse
+import json
+import os
+import re
+import tomllib
+
+from robotnix_common import save, checkout_git, ls_remote
+
+
+class AppReleaseDict(TypedDict):
+    url: str
+    tag: str
+    rev: str
+    sha256: str
+
+
+def version_key(tag: str) -> List[Tuple[int, Union[int, str]]]:
+    # Compare numeric components numerically so that 1.10 sorts after 1.9
+    return [(0, int(part)) if part.isdigit() else (1, part) for part in re.split(r'(\d+)', tag) if part]
+
+
+def newest_matching_tag(url: str, tag_pattern: str) -> Tuple[str, str]:
+    refs = ls_remote(url, ['refs/tags/*'])
+    tags = [ref[len('refs/tags/'):] for ref in refs
+            if ref.startswith('refs/tags/') and not ref.endswith('^{}')]
+    tags = [tag for tag in tags if re.fullmatch(tag_pattern, tag)]
+    if len(tags) == 0:
+        raise ValueError(f'{url} has no tags matching {tag_pattern}')
+
+    tag = max(tags, key=version_key)
+    # Peel annotated tags to the commit they point to
+    rev = refs.get(f'refs/tags/{tag}^{{}}', refs[f'refs/tags/{tag}'])
+    return tag, rev
+
+
+def pin_app_release(url: str, tag_pattern: str, prev: Any = None) -> AppReleaseDict:
+    tag, rev = newest_matching_tag(url, tag_pattern)
+    if prev is not None and prev.get('rev') == rev:
+        print(f'{url} is up to date at {tag}')
+        return AppReleaseDict(url=url, tag=tag, rev=rev, sha256=prev['sha256'])
+
+    git_info = checkout_git(url, f'refs/tags/{tag}', fetch_lfs=False)
+    return AppReleaseDict(url=url, tag=tag, rev=rev, sha256=git_info['sha256'])
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser(
+        description="pin the newest release tag of upstream app repos (e.g. Seedvault, Updater, Etar). "
+                    "The config is a TOML file with one table per app containing `url` and `tag_pattern` "
+                    "(a regex the whole tag name has to match)")
+    parser.add_argument('--out', default='app-releases.json', help="path to output file")
+    parser.add_argument('config', help="TOML file listing the app repos to pin")
+    args = parser.parse_args()
+
+    apps = tomllib.loads(open(args.config).read())
+
+    prev_data = json.load(open(args.out)) if os.path.exists(args.out) else {}
+    data: Dict[str, AppReleaseDict] = {}
+    for name, app in apps.items():
+        data[name] = pin_app_release(app['url'], app['tag_pattern'], prev_data.get(name))
+        save(args.out, data)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,52 @@
+#!/usr/bin/env python3
+# SPDX-FileCopyrightText: 2026 robotnix contributors
+# SPDX-License-Identifier: MIT
+
+from typing import TypedDict
+
+import argparse
+import json
+import re
+import subprocess
+
+from robotnix_common import save, github_api
+
+
+class ReleaseAssetDict(TypedDict):
+    version: str
+    url: str
+    sha256: str
+
+
+def pin_release_asset(repo: str, asset_pattern: str, include_prereleases: bool = False) -> ReleaseAssetDict:
+    """Pin the newest release asset of a GitHub repo (given as owner/name) whose name matches asset_pattern"""
+    for release in github_api(f'repos/{repo}/releases'):
+        if release['draft'] or (release['prerelease'] and not include_prereleases):
+            continue
+        for asset in release['assets']:
+            if re.fullmatch(asset_pattern, asset['name']):
+                url = asset['browser_download_url']
+                sha256 = subprocess.check_output(['nix-prefetch-url', url]).decode().strip()
+                return ReleaseAssetDict(version=release['tag_name'], url=url, sha256=sha256)
+
+    raise ValueError(f'No release of {repo} has an asset matching {asset_pattern}')
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser(
+        description="pin the newest GitHub release asset matching a regex, for prebuilts like adevtool")
+    parser.add_argument('--out', default=None, help="path to output file, prints to stdout by default")
+    parser.add_argument('--include-prereleases', action='store_true', help="also consider prereleases")
+    parser.add_argument('repo', help="GitHub repository, as owner/name")
+    parser.add_argument('asset_pattern', help="regex the whole asset file name has to match")
+    args = parser.parse_args()
+
+    data = pin_release_asset(args.repo, args.asset_pattern, args.include_prereleases)
+    if args.out is not None:
+        save(args.out, data)
+    else:
+        print(json.dumps(data, sort_keys=True, indent=2))
+
+
+if __name__ == "__main__":
+    main()
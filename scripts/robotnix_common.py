@@ -1,12 +1,27 @@
 # SPDX-FileCopyrightText: 2021 Daniel Fullmer and robotnix contributors
 # SPDX-License-Identifier: MIT
 
-from typing import Any, Dict, TypedDict, cast
+from typing import Any, Callable, Dict, Iterator, List, Optional, Sequence, Tuple, TypedDict, cast
 
+import argparse
+import atexit
+import contextlib
+import fnmatch
+import glob
 import json
 import os
+import re
+import shutil
 import subprocess
 import sys
+import tempfile
+import threading
+import time
+import tomllib
+import urllib.error
+import urllib.parse
+import urllib.request
+import zlib
 from pathlib import Path
 
 
@@ -27,13 +42,332 @@ def get_mirrored_url(url: str) -> str:
     return url
 
 
-def save(filename: str, data: Any) -> None:
-    open(filename, 'w').write(json.dumps(data, sort_keys=True, indent=2, separators=(',', ': ')))
+def normalize_url(url: str) -> str:
+    """Canonical form of a git URL, for use in cache keys and comparisons only.
+
+    URLs differing only by scheme/host case, a trailing slash or a .git suffix refer to
+    the same repo. The original URL should still be used for any output.
+    """
+    parsed = urllib.parse.urlsplit(url)
+    path = parsed.path.rstrip('/')
+    if path.endswith('.git'):
+        path = path[:-len('.git')]
+    return urllib.parse.urlunsplit((parsed.scheme.lower(), parsed.netloc.lower(), path, parsed.query, parsed.fragment))
+
+
+# Set ROBOTNIX_STAGING_DIR to write output files into this directory (under their absolute path) instead
+# of in place. Staged files are read in preference to the real ones, so updaters run one after another
+# see each other's output. commit_staged.py then moves them all into place together, and until then
+# an interrupted run leaves the real files as they were, consistent with each other.
+STAGING_DIR = os.environ.get('ROBOTNIX_STAGING_DIR', '')
+
+
+def staged_path(filename: str) -> str:
+    return os.path.join(STAGING_DIR, os.path.abspath(filename).lstrip('/'))
+
+
+def current_path(filename: str) -> str:
+    """Where to read an output file from: its staged version, if there is one"""
+    if STAGING_DIR and os.path.exists(staged_path(filename)):
+        return staged_path(filename)
+    return filename
+
+
+def load(filename: str, default: Any = None) -> Any:
+    """Read a json output file, or its staged version. Returns default (if given) if there is neither"""
+    path = current_path(filename)
+    if default is not None and not os.path.exists(path):
+        return default
+    return json.load(open(path))
+
+
+def replace_file(filename: str, text: str) -> None:
+    # Write to a temporary file first and rename it into place, so an interrupted run never leaves
+    # behind a truncated file. The temporary file is unique, since other processes might be saving
+    # the same file at the same time (like the shared caches)
+    mode = os.stat(filename).st_mode & 0o777 if os.path.exists(filename) else 0o644
+    fd, tmp_filename = tempfile.mkstemp(dir=os.path.dirname(filename) or '.', prefix=f'.{os.path.basename(filename)}.')
+    try:
+        with os.fdopen(fd, 'w') as f:
+            f.write(text)
+        os.chmod(tmp_filename, mode)
+        os.replace(tmp_filename, filename)
+    except BaseException:
+        os.remove(tmp_filename)
+        raise
+
+
+def write_file(filename: str, text: str, stage: bool = True) -> None:
+    if stage and STAGING_DIR:
+        filename = staged_path(filename)
+        os.makedirs(os.path.dirname(filename), exist_ok=True)
+    replace_file(filename, text)
+
+
+def save(filename: str, data: Any, stage: bool = True) -> None:
+    write_file(filename, json.dumps(data, sort_keys=True, indent=2, separators=(',', ': ')), stage)
+
+
+@contextlib.contextmanager
+def staged_outputs() -> Iterator[None]:
+    """Stage the output files written within, and commit them together at the end. Does nothing if
+    ROBOTNIX_STAGING_DIR is set already, then whoever set it commits them (like update.sh)"""
+    global STAGING_DIR
+    if STAGING_DIR:
+        yield
+        return
+    STAGING_DIR = tempfile.mkdtemp(prefix='robotnix-staging-')
+    try:
+        yield
+        commit_staged(STAGING_DIR)
+    except BaseException:
+        if len(os.listdir(STAGING_DIR)) > 0:
+            print(f"Output files were not updated. To resume, run again with ROBOTNIX_STAGING_DIR={STAGING_DIR}",
+                  file=sys.stderr)
+        else:
+            shutil.rmtree(STAGING_DIR)
+        raise
+    finally:
+        STAGING_DIR = ''
+
+
+def commit_staged(staging_dir: str) -> List[str]:
+    """Move every file staged in staging_dir into place, and remove staging_dir. Returns the files moved"""
+    committed = []
+    for root, _, files in os.walk(staging_dir):
+        for filename in files:
+            staged = os.path.join(root, filename)
+            target = '/' + os.path.relpath(staged, staging_dir)
+            # Copied, since staging_dir may be on another file system than the target
+            replace_file(target, open(staged).read())
+            committed.append(target)
+    shutil.rmtree(staging_dir)
+    return sorted(committed)
+
+
+# Fields of output file entries which don't affect what gets built: details nix-prefetch-git reports
+# about the checkout it made, all derived from url and rev anyway
+COSMETIC_KEYS = {'date', 'path', 'tree', 'deepClone', 'leaveDotGit'}
+# Fields which mean the same whether they're left out or set to these
+DEFAULT_VALUES: Dict[str, Any] = {'fetchSubmodules': False, 'groups': [], 'lineage_recovery': False}
+# Lists in which order doesn't matter
+UNORDERED_KEYS = {'deps', 'groups'}
+
+
+def semantic_entry(entry: Any) -> Any:
+    """Canonical form of an output file entry (source or device metadata), for comparisons only"""
+    if not isinstance(entry, dict):
+        return entry
+    result = {}
+    for key, value in entry.items():
+        if key in COSMETIC_KEYS or (key in DEFAULT_VALUES and value == DEFAULT_VALUES[key]):
+            continue
+        if key == 'url':
+            value = normalize_url(value)
+        elif key in UNORDERED_KEYS and isinstance(value, list):
+            value = sorted(value)
+        result[key] = value
+    return result
+
+
+def same_entry(old: Any, new: Any) -> bool:
+    return bool(semantic_entry(old) == semantic_entry(new))
+
+
+class EntryChange(TypedDict):
+    change: str  # One of '+', '-', '~'
+    key: str
+    old_rev: Optional[str]
+    new_rev: Optional[str]
+
+
+def compare_entries(old: Dict[str, Any], new: Dict[str, Any]) -> List[EntryChange]:
+    """Find added, removed and changed entries between two versions of an output file"""
+    def rev(entry: Any) -> Optional[str]:
+        return entry.get('rev') if isinstance(entry, dict) else None
+
+    changes: List[EntryChange] = []
+    for key in sorted(old.keys() | new.keys()):
+        if key not in new:
+            changes.append({'change': '-', 'key': key, 'old_rev': rev(old[key]), 'new_rev': None})
+        elif key not in old:
+            changes.append({'change': '+', 'key': key, 'old_rev': None, 'new_rev': rev(new[key])})
+        elif not same_entry(old[key], new[key]):
+            changes.append({'change': '~', 'key': key, 'old_rev': rev(old[key]), 'new_rev': rev(new[key])})
+    return changes
+
+
+def diff_entries(old: Dict[str, Any], new: Dict[str, Any]) -> List[str]:
+    """Summarize added, removed and changed entries between two versions of an output file"""
+    lines: List[str] = []
+    for c in compare_entries(old, new):
+        if c['change'] == '~' and c['old_rev'] is not None and c['new_rev'] is not None \
+                and c['old_rev'] != c['new_rev']:
+            lines.append(f"~ {c['key']}: {c['old_rev'][:12]} -> {c['new_rev'][:12]}")
+        else:
+            lines.append(f"{c['change']} {c['key']}")
+    return lines
+
+
+def check_up_to_date(filename: str, data: Any) -> bool:
+    """Used by --frozen: compare freshly generated data against what is on disk and report any differences"""
+    old_data = load(filename, {})
+    changes = diff_entries(old_data, data)
+    if len(changes) > 0:
+        print(f'{filename} is out of date:', file=sys.stderr)
+        for line in changes:
+            print(f'  {line}', file=sys.stderr)
+    return len(changes) == 0
+
+
+def in_shard(relpath: str, shard: Tuple[int, int]) -> bool:
+    # Use a hash that is stable across runs (unlike hash()), so every shard agrees on the partitioning
+    index, count = shard
+    return zlib.crc32(relpath.encode()) % count == index
+
+
+def parse_shard(value: str) -> Tuple[int, int]:
+    index, count = (int(x) for x in value.split('/'))
+    if not 0 <= index < count:
+        raise argparse.ArgumentTypeError(f"invalid shard {value}, expected i/n with 0 <= i < n")
+    return index, count
+
+
+# Retries for transient network errors, see check_output_with_retries
+RETRIES = 3
+RETRY_DELAY = 5.0  # Seconds before the first retry, doubled after each one
+
+# What git prints when a repo or ref really doesn't exist or isn't accessible. Retrying won't help with those,
+# and callers rely on getting the error quickly (e.g. to probe whether a device repo exists).
+PERMANENT_ERROR_RE = re.compile(
+    r"not found|does not exist|could not read Username|Authentication failed|returned error: 40[134]|"
+    r"couldn't find remote ref",
+    re.IGNORECASE)
+
+
+# GitHub (and others) answer too many requests with HTTP 429 or "abuse detection" errors. Those want a
+# longer pause than other transient errors. git doesn't tell us how long, so wait at least this long.
+RATE_LIMIT_RE = re.compile(r"returned error: 429|rate limit|abuse", re.IGNORECASE)
+RATE_LIMIT_DELAY = 60.0
+
+# How much of the end of stderr to keep for classifying errors. The rest (like nix-prefetch-git's
+# progress output) is only passed through
+STDERR_TAIL = 8192
+
+
+HOST_LIMITS: Dict[str, Dict[str, Any]] = {}  # host: limits, see read_host_limits
+HOST_STATE: Dict[str, Tuple[Any, threading.Lock, List[float]]] = {}  # host: (connections, lock, [next start])
+HOST_STATE_LOCK = threading.Lock()
+
+
+def set_host_limits(host_limits: Dict[str, Dict[str, Any]]) -> None:
+    global HOST_LIMITS
+    HOST_LIMITS = host_limits
+    HOST_STATE.clear()
+
+
+@contextlib.contextmanager
+def host_limited(url: str) -> Iterator[None]:
+    """Keep to the limits configured for the host of url while fetching from it"""
+    host = urllib.parse.urlsplit(url).hostname or ''
+    if host not in HOST_LIMITS:
+        yield
+        return
+    limits = HOST_LIMITS[host]
+    with HOST_STATE_LOCK:
+        if host not in HOST_STATE:
+            connections = threading.BoundedSemaphore(limits['max_connections']) \
+                if 'max_connections' in limits else contextlib.nullcontext()
+            HOST_STATE[host] = (connections, threading.Lock(), [0.0])
+        connections, lock, next_start = HOST_STATE[host]
+    with connections:
+        # Reserve a start time, so concurrent requests are spread out instead of all waiting the same delay
+        with lock:
+            start = max(time.monotonic(), next_start[0])
+            next_start[0] = start + limits.get('min_delay', 0.0)
+        time.sleep(max(0.0, start - time.monotonic()))
+        yield
+
+
+def configure_retries(retries: int, delay: float) -> None:
+    global RETRIES, RETRY_DELAY
+    RETRIES = retries
+    RETRY_DELAY = delay
+
+
+def run_streaming_stderr(args: List[str], env: Optional[Dict[str, str]]) -> subprocess.CompletedProcess[bytes]:
+    """Run args, passing stderr through as it comes. The CompletedProcess only has the tail of stderr"""
+    process = subprocess.Popen(args, stdout=subprocess.PIPE, stderr=subprocess.PIPE, env=env)
+    assert process.stdout is not None and process.stderr is not None
+    tail = bytearray()
+
+    def forward_stderr(stderr: Any) -> None:
+        # read1 returns whatever is available, so progress lines ending in \r show up right away
+        for chunk in iter(lambda: stderr.read1(4096), b''):
+            sys.stderr.write(chunk.decode(errors='replace'))
+            sys.stderr.flush()
+            tail.extend(chunk)
+            del tail[:-STDERR_TAIL]
+
+    forwarder = threading.Thread(target=forward_stderr, args=(process.stderr,))
+    forwarder.start()
+    stdout = process.stdout.read()
+    forwarder.join()
+    return subprocess.CompletedProcess(args, process.wait(), stdout, bytes(tail))
+
+
+def is_permanent_error(error: subprocess.CalledProcessError) -> bool:
+    """Whether a command run by check_output_with_retries failed since the repo or ref really isn't there.
+    Otherwise the retries ran out on transient errors, and running again later might work"""
+    return PERMANENT_ERROR_RE.search((error.stderr or b'').decode(errors='replace')) is not None
+
+
+def check_output_with_retries(args: List[str], env: Optional[Dict[str, str]] = None) -> bytes:
+    """subprocess.check_output, but retry with exponential backoff unless the error is a permanent one"""
+    # Fail instead of waiting for credentials when a repo is private or gone (GitHub asks for them then)
+    env = {**(os.environ if env is None else env), 'GIT_TERMINAL_PROMPT': '0'}
+    delay = RETRY_DELAY
+    attempt = 0
+    while True:
+        result = run_streaming_stderr(args, env)
+        stderr = result.stderr.decode(errors='replace')
+        if result.returncode == 0:
+            return result.stdout
+        if attempt >= RETRIES or PERMANENT_ERROR_RE.search(stderr):
+            raise subprocess.CalledProcessError(result.returncode, args, result.stdout, result.stderr)
+        attempt += 1
+        if RATE_LIMIT_RE.search(stderr):
+            delay = max(delay, RATE_LIMIT_DELAY)
+            print(f"Rate limited by the remote, pausing for {delay:g}s ({attempt}/{RETRIES})", file=sys.stderr)
+        else:
+            print(f"{' '.join(args)} failed, retrying in {delay:g}s ({attempt}/{RETRIES})", file=sys.stderr)
+        time.sleep(delay)
+        delay *= 2
+
+
+# Set with --offline: only use what is already on disk (output files and caches), and fail with a
+# clear error instead of contacting any remote. For reproducing old runs and air-gapped CI.
+OFFLINE = False
+
+
+class OfflineError(Exception):
+    pass
+
+
+def set_offline(offline: bool) -> None:
+    global OFFLINE
+    OFFLINE = offline
+
+
+def require_network(url: str) -> None:
+    """Fail if url would have to be fetched over the network while running offline. Local mirrors are fine"""
+    if OFFLINE and not get_mirrored_url(url).startswith('/'):
+        raise OfflineError(f"{url} isn't cached, but running with --offline. Run once without it to fill the caches")
+
 
 def get_store_path(path):
     """Get actual path to a Nix store path; supports handling local remotes"""
     prefix = os.getenv("NIX_REMOTE")
-    print(repr(prefix))
 
     if not prefix or prefix == "daemon":
         return path
@@ -49,6 +383,191 @@ def get_store_path(path):
     return str(remote_path)
 
 
+# Every nix-prefetch-git invocation gets its own temporary directory with this prefix, which is removed
+# afterwards even if nix-prefetch-git didn't clean up after itself (e.g. because it was killed)
+PREFETCH_TMP_PREFIX = 'robotnix-prefetch-'
+KEEP_TMP = False  # Set with --keep-tmp, to inspect what a failing nix-prefetch-git left behind
+
+
+def set_keep_tmp(keep_tmp: bool) -> None:
+    global KEEP_TMP
+    KEEP_TMP = keep_tmp
+
+
+def clean_stale_prefetch_dirs(max_age: float = 24 * 60 * 60) -> None:
+    """Remove temporary directories left behind by earlier runs that were interrupted"""
+    for path in glob.glob(os.path.join(tempfile.gettempdir(), f'{PREFETCH_TMP_PREFIX}*')):
+        # Only old ones, since another run might still be using the newer ones
+        if time.time() - os.path.getmtime(path) > max_age:
+            print(f"Removing stale temporary directory {path}")
+            shutil.rmtree(path, ignore_errors=True)
+
+
+# The common options can also be set in config files and in the environment, e.g. by a Nix devshell.
+# From lowest to highest precedence: built-in defaults, the system, user and project config files
+# (or the one given with --config instead of the project's), environment variables and the command
+# line. Config files use the option names as keys, like `retries = 5`. Flags are enabled in the
+# environment by setting their variable to 1
+COMMON_OPTION_ENV = {
+    'retries': 'ROBOTNIX_RETRIES',
+    'retry_delay': 'ROBOTNIX_RETRY_DELAY',
+    'keep_tmp': 'ROBOTNIX_KEEP_TMP',
+    'offline': 'ROBOTNIX_OFFLINE',
+}
+COMMON_OPTION_DEFAULTS = {'retries': RETRIES, 'retry_delay': RETRY_DELAY, 'keep_tmp': KEEP_TMP, 'offline': OFFLINE}
+SYSTEM_CONFIG_FILE = '/etc/robotnix/updater.toml'
+USER_CONFIG_FILE = os.path.join(os.environ.get('XDG_CONFIG_HOME', os.path.expanduser('~/.config')),
+                                'robotnix', 'updater.toml')
+PROJECT_CONFIG_FILE = os.path.join(os.path.dirname(os.path.dirname(os.path.abspath(__file__))),
+                                   '.robotnix-updater.toml')
+
+
+# Config files can also limit how the updaters fetch from a host, in [hosts."<host>"] tables. Some
+# self-hosted Gerrit servers ban clients which don't keep to such limits. min-delay is the number of
+# seconds between the starts of two requests to the host, max-connections how many may run at once
+HOST_LIMIT_KEYS = {'min_delay': float, 'max_connections': int}
+
+
+def read_host_limits(path: str, hosts: Any) -> Dict[str, Dict[str, Any]]:
+    host_limits = {}
+    for host, table in hosts.items():
+        limits = {}
+        for key, value in table.items():
+            limit = key.replace('-', '_')
+            if limit not in HOST_LIMIT_KEYS:
+                print(f"WARNING: unknown host limit {key} for {host} in {path}", file=sys.stderr)
+                continue
+            if isinstance(value, bool) or not isinstance(value, (int, float)) or value < 0 or \
+                    (limit == 'max_connections' and (not isinstance(value, int) or value < 1)):
+                raise ValueError(f"{key} for {host} in {path} should be a positive {HOST_LIMIT_KEYS[limit].__name__}")
+            limits[limit] = HOST_LIMIT_KEYS[limit](value)
+        host_limits[host] = limits
+    return host_limits
+
+
+def read_config_file(path: str) -> Tuple[Dict[str, Any], Dict[str, Dict[str, Any]]]:
+    """The common options and the host limits set in a config file"""
+    options = {}
+    host_limits: Dict[str, Dict[str, Any]] = {}
+    for key, value in tomllib.loads(open(path).read()).items():
+        if key == 'hosts':
+            host_limits = read_host_limits(path, value)
+            continue
+        option = key.replace('-', '_')
+        if option not in COMMON_OPTION_DEFAULTS:
+            print(f"WARNING: unknown option {key} in {path}", file=sys.stderr)
+            continue
+        default = COMMON_OPTION_DEFAULTS[option]
+        if isinstance(value, bool) != isinstance(default, bool) or not isinstance(value, (int, float)):
+            raise ValueError(f"{key} in {path} should be a {type(default).__name__}")
+        options[option] = type(default)(value)
+    return options, host_limits
+
+
+def config_files(args: argparse.Namespace) -> List[str]:
+    """The config files which exist, from lowest to highest precedence"""
+    paths = [SYSTEM_CONFIG_FILE, USER_CONFIG_FILE]
+    if args.config is not None:
+        if not os.path.exists(args.config):
+            raise FileNotFoundError(f"config file {args.config} doesn't exist")
+        paths.append(args.config)
+    else:
+        paths.append(PROJECT_CONFIG_FILE)
+    return [path for path in paths if os.path.exists(path)]
+
+
+def resolve_common_options(args: argparse.Namespace) -> Dict[str, Tuple[Any, str]]:
+    """The effective value of each common option, and where it comes from"""
+    resolved = {option: (default, 'default') for option, default in COMMON_OPTION_DEFAULTS.items()}
+    for path in config_files(args):
+        resolved.update({option: (value, path) for option, value in read_config_file(path)[0].items()})
+    for option, env in COMMON_OPTION_ENV.items():
+        if env in os.environ:
+            default = COMMON_OPTION_DEFAULTS[option]
+            value = os.environ[env] == '1' if isinstance(default, bool) else type(default)(os.environ[env])
+            resolved[option] = (value, f'${env}')
+    for option in COMMON_OPTION_DEFAULTS:
+        if getattr(args, option) is not None:
+            resolved[option] = (getattr(args, option), 'command line')
+    return resolved
+
+
+def resolve_host_limits(args: argparse.Namespace) -> Dict[str, Tuple[Dict[str, Any], str]]:
+    """The limits for each host, and the config file they come from. A host's table in a config file
+    replaces the ones in those with lower precedence"""
+    resolved = {}
+    for path in config_files(args):
+        resolved.update({host: (limits, path) for host, limits in read_config_file(path)[1].items()})
+    return resolved
+
+
+def add_common_args(parser: argparse.ArgumentParser) -> None:
+    """Options every updater has, for how it fetches. Apply them with apply_common_args"""
+    # Left as None, so apply_common_args can tell which were given and fill in the others
+    parser.add_argument('--retries', type=int,
+                        help=f"how often to retry fetches failing with transient (network) errors (default {RETRIES})")
+    parser.add_argument('--retry-delay', type=float,
+                        help="seconds to wait before the first retry, doubled for every subsequent one "
+                        f"(default {RETRY_DELAY:g})")
+    parser.add_argument('--keep-tmp', action='store_true', default=None,
+                        help="keep nix-prefetch-git's temporary directories instead of removing them, for debugging")
+    parser.add_argument('--offline', action='store_true', default=None,
+                        help="don't contact any remotes, only use existing output files and the ls-remote and "
+                        "checkout caches. Fails if anything needed isn't cached")
+    parser.add_argument('--config', metavar='FILE',
+                        help=f"config file to read instead of {os.path.basename(PROJECT_CONFIG_FILE)} of the "
+                        f"project, on top of {SYSTEM_CONFIG_FILE} and {USER_CONFIG_FILE}")
+    parser.add_argument('--show-config', action='store_true',
+                        help="print the effective settings and where each one comes from, then exit")
+
+
+def show_config(options: Dict[str, Tuple[Any, str]], host_limits: Dict[str, Tuple[Dict[str, Any], str]]) -> None:
+    """Print the common options, the host limits and the settings only taken from the environment, with
+    their source"""
+    for option, (value, source) in options.items():
+        print(f"{option.replace('_', '-'):<26} {value}  ({source})")
+    for host, (limits, source) in sorted(host_limits.items()):
+        described = ' '.join(f"{limit.replace('_', '-')}={value}" for limit, value in limits.items())
+        print(f"{'host ' + host:<26} {described or 'no limits'}  ({source})")
+
+    env_settings = [
+        ('git-mirrors', 'ROBOTNIX_GIT_MIRRORS', ROBOTNIX_GIT_MIRRORS or None),
+        ('staging-dir', 'ROBOTNIX_STAGING_DIR', STAGING_DIR or None),
+        ('checkout-cache', 'ROBOTNIX_CHECKOUT_CACHE', CHECKOUT_CACHE_FILE if CHECKOUT_CACHE_ENABLED else None),
+        ('ls-remote-cache-ttl', 'ROBOTNIX_LS_REMOTE_CACHE_TTL', LS_REMOTE_CACHE_TTL),
+        ('ls-remote-cache-file', 'ROBOTNIX_LS_REMOTE_CACHE_FILE', LS_REMOTE_CACHE_FILE),
+        ('github-token', 'GITHUB_TOKEN', '(set)' if 'GITHUB_TOKEN' in os.environ else None),
+        ('tmpdir', 'TMPDIR', os.environ.get('TMPDIR', '/tmp')),
+    ]
+    for name, env, setting in env_settings:
+        print(f"{name:<26} {setting}  ({f'${env}' if env in os.environ else 'default'})")
+
+
+def apply_common_args(args: argparse.Namespace) -> None:
+    options = resolve_common_options(args)
+    for option, (value, _) in options.items():
+        setattr(args, option, value)
+    host_limits = resolve_host_limits(args)
+    if args.show_config:
+        show_config(options, host_limits)
+        sys.exit(0)
+    set_host_limits({host: limits for host, (limits, _) in host_limits.items()})
+    configure_retries(args.retries, args.retry_delay)
+    set_keep_tmp(args.keep_tmp)
+    set_offline(args.offline)
+    clean_stale_prefetch_dirs()
+
+
+def run_main(main: Callable[[], None], staged: bool = False) -> None:
+    """Run an updater's main(). Missing cache entries with --offline are reported as an error message
+    instead of a traceback. With staged, the output files are only moved into place if main() succeeds"""
+    try:
+        with staged_outputs() if staged else contextlib.nullcontext():
+            main()
+    except OfflineError as e:
+        sys.exit(f'ERROR: {e}')
+
+
 class GitCheckoutInfoDict(TypedDict):
     """Container for output from nix-prefetch-git"""
     url: str
@@ -61,20 +580,88 @@ class GitCheckoutInfoDict(TypedDict):
     leaveDotGit: str
 
 
+# nix-prefetch-git results can optionally be kept on disk, so that a commit which was already checked
+# out by an earlier run (or by another updater) isn't downloaded again. Since commits never change,
+# entries don't expire, but they're only used while their store path hasn't been garbage collected.
+# Set ROBOTNIX_CHECKOUT_CACHE=1 to enable this.
+CHECKOUT_CACHE_ENABLED = os.environ.get('ROBOTNIX_CHECKOUT_CACHE', '0') == '1'
+CHECKOUT_CACHE_FILE = os.path.join(os.environ.get('XDG_CACHE_HOME', os.path.expanduser('~/.cache')),
+                                   'robotnix', 'checkouts.json')
+CHECKOUT_CACHE: Optional[Dict[str, GitCheckoutInfoDict]] = None  # "<normalized url> <rev> <flags>": checkout
+CHECKOUT_CACHE_LOCK = threading.Lock()
+
+
+def _load_checkout_cache() -> Dict[str, GitCheckoutInfoDict]:
+    global CHECKOUT_CACHE
+    if CHECKOUT_CACHE is None:
+        if os.path.exists(CHECKOUT_CACHE_FILE):
+            CHECKOUT_CACHE = cast(Dict[str, GitCheckoutInfoDict], json.load(open(CHECKOUT_CACHE_FILE)))
+        else:
+            CHECKOUT_CACHE = {}
+        atexit.register(_save_checkout_cache)
+    return CHECKOUT_CACHE
+
+
+def _save_checkout_cache() -> None:
+    if CHECKOUT_CACHE is None:
+        return
+    os.makedirs(os.path.dirname(CHECKOUT_CACHE_FILE), exist_ok=True)
+    save(CHECKOUT_CACHE_FILE, CHECKOUT_CACHE, stage=False)
+
+
+def _checkout_cache_key(url: str, rev: str, fetch_submodules: bool, fetch_lfs: bool) -> Optional[str]:
+    if not re.fullmatch('[0-9a-f]{40}', rev):
+        # Refs move, so look up the commit they currently point to
+        commit = resolve_ref(url, rev)
+        if commit is None:
+            return None
+        rev = commit
+    return f'{normalize_url(url)} {rev} {int(fetch_submodules)}{int(fetch_lfs)}'
+
+
 def checkout_git(
     url: str,
     rev: str,
     fetch_submodules: bool = False,
     fetch_lfs: bool = True,
 ) -> GitCheckoutInfoDict:
+    cache_key = None
+    if CHECKOUT_CACHE_ENABLED or OFFLINE:
+        cache_key = _checkout_cache_key(url, rev, fetch_submodules, fetch_lfs)
+        with CHECKOUT_CACHE_LOCK:
+            cached = _load_checkout_cache().get(cache_key) if cache_key is not None else None
+        if cached is not None and os.path.exists(get_store_path(cached['path'])):
+            print(f"Reusing earlier checkout of {url} {rev}")
+            return cast(GitCheckoutInfoDict, dict(cached))
+
+    require_network(url)
     print("Checking out %s %s" % (url, rev))
     args = ["nix-prefetch-git", "--url", url, "--rev", rev]
     if fetch_submodules:
         args.append("--fetch-submodules")
     if fetch_lfs:
         args.append("--fetch-lfs")
-    json_text = subprocess.check_output(args).decode()
-    return cast(GitCheckoutInfoDict, json.loads(json_text))
+    tmpdir = tempfile.mkdtemp(prefix=PREFETCH_TMP_PREFIX)
+    try:
+        with host_limited(url):
+            json_text = check_output_with_retries(args, env={**os.environ, 'TMPDIR': tmpdir}).decode()
+    finally:
+        if KEEP_TMP:
+            print(f"Keeping temporary directory {tmpdir}")
+        else:
+            shutil.rmtree(tmpdir, ignore_errors=True)
+    git_info = cast(GitCheckoutInfoDict, json.loads(json_text))
+    if cache_key is not None:
+        with CHECKOUT_CACHE_LOCK:
+            _load_checkout_cache()[cache_key] = git_info
+    return cast(GitCheckoutInfoDict, dict(git_info))
+
+
+def uses_lfs(path: str) -> bool:
+    """Whether a checkout has files tracked by Git LFS. Only looks at the top-level .gitattributes, which
+    is where repos using LFS set it up in practice"""
+    gitattributes = os.path.join(path, '.gitattributes')
+    return os.path.exists(gitattributes) and 'filter=lfs' in open(gitattributes, errors='replace').read()
 
 
 def check_free_space() -> None:
@@ -94,20 +681,137 @@ def check_free_space() -> None:
               )
 
 
-REMOTE_REFS: Dict[str, Dict[str, str]] = {}  # url: { ref: rev }
+def github_rate_limit_delay(error: urllib.error.HTTPError) -> Optional[float]:
+    """How long GitHub wants us to wait before trying again, or None if error isn't about rate limiting"""
+    # See https://docs.github.com/en/rest/using-the-rest-api/rate-limits-for-the-rest-api
+    if error.code not in [403, 429]:
+        return None
+    if 'Retry-After' in error.headers:
+        return float(error.headers['Retry-After'])
+    if error.headers.get('X-RateLimit-Remaining') == '0' and 'X-RateLimit-Reset' in error.headers:
+        return max(float(error.headers['X-RateLimit-Reset']) - time.time(), 0) + 1
+    if error.code == 429:
+        return RATE_LIMIT_DELAY
+    # Other 403s are about permissions
+    return None
 
 
-def ls_remote(url: str) -> Dict[str, str]:
-    if url in REMOTE_REFS:
-        return REMOTE_REFS[url]
+def github_api(path: str) -> Any:
+    """Query the GitHub REST API, authenticating with $GITHUB_TOKEN if it is set"""
+    require_network(f'https://api.github.com/{path}')
+    request = urllib.request.Request(f'https://api.github.com/{path}')
+    if 'GITHUB_TOKEN' in os.environ:
+        request.add_header('Authorization', f"Bearer {os.environ['GITHUB_TOKEN']}")
+    attempt = 0
+    while True:
+        try:
+            with host_limited(request.full_url):
+                return json.load(urllib.request.urlopen(request))
+        except urllib.error.HTTPError as e:
+            delay = github_rate_limit_delay(e)
+            if delay is None or attempt >= RETRIES:
+                raise
+            attempt += 1
+            print(f"Rate limited by the GitHub API, pausing for {delay:g}s ({attempt}/{RETRIES})", file=sys.stderr)
+            time.sleep(delay)
 
-    orig_url = url
+
+REMOTE_REFS: Dict[str, Dict[str, str]] = {}  # normalized url: { ref: rev }
+
+# ls-remote results can optionally be kept on disk for a while, so that running several updaters
+# in a row (like update.sh does for each branch) doesn't query the same repos again every time.
+# Set ROBOTNIX_LS_REMOTE_CACHE_TTL to the number of seconds entries stay valid to enable this, and
+# ROBOTNIX_LS_REMOTE_CACHE_FILE to keep them somewhere else than the user's cache dir.
+LS_REMOTE_CACHE_TTL = int(os.environ.get('ROBOTNIX_LS_REMOTE_CACHE_TTL', '0'))
+LS_REMOTE_CACHE_FILE = os.environ.get('ROBOTNIX_LS_REMOTE_CACHE_FILE') or \
+    os.path.join(os.environ.get('XDG_CACHE_HOME', os.path.expanduser('~/.cache')), 'robotnix', 'ls-remote.json')
+LS_REMOTE_CACHE: Optional[Dict[str, Any]] = None  # normalized url: { 'time': unix time, 'refs': { ref: rev } }
+LS_REMOTE_CACHE_LOCK = threading.Lock()
+
+
+def _load_ls_remote_cache() -> Dict[str, Any]:
+    global LS_REMOTE_CACHE
+    if LS_REMOTE_CACHE is None:
+        if os.path.exists(LS_REMOTE_CACHE_FILE):
+            LS_REMOTE_CACHE = cast(Dict[str, Any], json.load(open(LS_REMOTE_CACHE_FILE)))
+        else:
+            LS_REMOTE_CACHE = {}
+        # Written once at exit instead of after every query, since the file can get big
+        atexit.register(_save_ls_remote_cache)
+    return LS_REMOTE_CACHE
+
+
+def _save_ls_remote_cache() -> None:
+    # Nothing new was queried offline, and expiring entries would only lose refs we can't get back yet
+    if LS_REMOTE_CACHE is None or OFFLINE:
+        return
+    now = time.time()
+    fresh = {url: entry for url, entry in LS_REMOTE_CACHE.items() if now - entry['time'] < LS_REMOTE_CACHE_TTL}
+    os.makedirs(os.path.dirname(LS_REMOTE_CACHE_FILE), exist_ok=True)
+    save(LS_REMOTE_CACHE_FILE, fresh, stage=False)
+
+
+def _cached_refs(cache_key: str) -> Optional[Dict[str, str]]:
+    if cache_key in REMOTE_REFS:
+        return REMOTE_REFS[cache_key]
+    if LS_REMOTE_CACHE_TTL > 0 or OFFLINE:
+        with LS_REMOTE_CACHE_LOCK:
+            entry = _load_ls_remote_cache().get(cache_key)
+        # Offline, outdated refs are better than none
+        if entry is not None and (OFFLINE or time.time() - entry['time'] < LS_REMOTE_CACHE_TTL):
+            REMOTE_REFS[cache_key] = entry['refs']
+            return cast(Dict[str, str], entry['refs'])
+    return None
+
+
+# Whether git ls-remote would list ref for pattern: the pattern has to match the end of the ref, starting
+# at a path component
+def ref_matches(ref: str, pattern: str) -> bool:
+    components = ref.split('/')
+    return any(fnmatch.fnmatchcase('/'.join(components[i:]), pattern) for i in range(len(components)))
+
+
+def ls_remote(url: str, patterns: Sequence[str] = ()) -> Dict[str, str]:
+    """List the refs of a remote. With patterns (like 'refs/heads/lineage-*', see git ls-remote), only
+    matching refs are transferred, which matters for repos with thousands of tags. The result may still
+    contain other refs if a full listing of the repo was already around."""
+    full_key = normalize_url(url)
+    cache_key = f"{full_key} {' '.join(patterns)}" if len(patterns) > 0 else full_key
+    cached = _cached_refs(cache_key)
+    if cached is not None:
+        return cached
+    cached = _cached_refs(full_key)
+    # A full listing doesn't include every ref a server has, like Gerrit's refs/changes/, so it only answers
+    # queries for refs it actually lists. Offline, it's the best we have anyway
+    if cached is not None and (OFFLINE or any(ref_matches(ref, pattern) for ref in cached for pattern in patterns)):
+        return cached
+
+    require_network(url)
     url = get_mirrored_url(url)
 
-    remote_info = subprocess.check_output(["git", "ls-remote", url]).decode()
-    REMOTE_REFS[orig_url] = {}
+    with host_limited(url):
+        remote_info = check_output_with_retries(["git", "ls-remote", url, *patterns]).decode()
+    # Build the whole dict before publishing it, since ls_remote is called from
+    # concurrent worker threads which must never see a partially filled entry
+    refs = {}
     for line in remote_info.split('\n'):
         if line:
             ref, rev = reversed(line.split('\t'))
-            REMOTE_REFS[orig_url][ref] = rev
-    return REMOTE_REFS[orig_url]
+            refs[ref] = rev
+    REMOTE_REFS[cache_key] = refs
+    if LS_REMOTE_CACHE_TTL > 0:
+        with LS_REMOTE_CACHE_LOCK:
+            _load_ls_remote_cache()[cache_key] = {'time': time.time(), 'refs': refs}
+    return refs
+
+
+def resolve_ref(url: str, ref: str) -> Optional[str]:
+    """The commit ref points to, or None if the remote doesn't have it. ref can also be just a tag or branch
+    name. Annotated tags are peeled to the commit they point to, since that's what Nix checks out"""
+    # Asking for the ref also gets refs a full listing doesn't advertise, like Gerrit's refs/changes/. The
+    # peeled commit of a tag is listed as <tag>^{}, which doesn't match the pattern of the tag itself.
+    refs = ls_remote(url, [ref, f'{ref}^{{}}'])
+    for candidate in [ref, f'refs/tags/{ref}', f'refs/heads/{ref}']:
+        if candidate in refs:
+            return refs.get(f'{candidate}^{{}}', refs[candidate])
+    return None
@@ -8,9 +8,17 @@ import subprocess
 from unittest.mock import patch
 import pytest
 
-from typing import Any, Optional
+from typing import Any, Dict, Iterator, Optional
 
 import mk_repo_file
+from robotnix_common import save
+
+
+@pytest.fixture(autouse=True)
+def empty_caches() -> Iterator[None]:
+    # Hashes cached by one test must not end up in the next one
+    with patch.dict(mk_repo_file.revInfo, clear=True), patch.dict(mk_repo_file.treeInfo, clear=True):
+        yield
 
 
 def git_create(directory: str, tag: Optional[str] = "release", initial_branch: str = "main") -> None:
@@ -68,8 +76,8 @@ def test_basic(tmpdir: Any, manifest_repo: Any) -> None:
 
     # Removing just one sha256 and resuming
     del data['b']['sha256']
-    with patch('mk_repo_file.ls_remote') as ls_remote:
-        ls_remote.side_effect = Exception('Called ls-remote')
+    with patch('mk_repo_file.resolve_ref') as resolve_ref:
+        resolve_ref.side_effect = Exception('Called ls-remote')
         data = mk_repo_file.make_repo_file(manifest_repo, "release", prev_data=data)
         assert 'sha256' in data['b']
 
@@ -93,3 +101,111 @@ def test_read_cached_repo_json(tmpdir: Any) -> None:
     mk_repo_file.read_cached_repo_json(top)
     assert mk_repo_file.revInfo['foo', True] == {'sha256': 'bar', 'tree': 'foo2', 'dateTime': 1}
     assert mk_repo_file.treeInfo['foo2', True] == {'sha256': 'bar', 'tree': 'foo2', 'dateTime': 1}
+
+
+# Output of nix-prefetch-git, as recorded for a real project
+RECORDED_PREFETCH_OUTPUT = {
+    'url': 'https://android.googlesource.com/platform/art',
+    'rev': '93f517ff8a15cad20a0b49947397592d1824dc98',
+    'date': '2024-09-24T01:01:25+02:00',
+    'path': '/nix/store/cqzpnkg7b7l7x0m9cx8xbxrjvzl6xc7h-art',
+    'sha256': '0ca0bb7cdjvv15238n3v3wya08z3k4l41b99zyb5zrsiwm117nsm',
+    'hash': 'sha256-6Y5vt+tW8bqFDLUTRVbZdEGXa0H5SWtRIwQy2kTpeYc=',
+    'fetchLFS': True,
+    'fetchSubmodules': False,
+    'deepClone': False,
+    'leaveDotGit': False,
+}
+
+GOLDEN_REPO_JSON = '''{
+  "art": {
+    "dateTime": 1727132485,
+    "groups": [
+      "pdk"
+    ],
+    "rev": "93f517ff8a15cad20a0b49947397592d1824dc98",
+    "revisionExpr": "refs/tags/android-15.0.0_r6",
+    "sha256": "0ca0bb7cdjvv15238n3v3wya08z3k4l41b99zyb5zrsiwm117nsm",
+    "url": "https://android.googlesource.com/platform/art"
+  }
+}'''
+
+
+def test_output_is_byte_stable(tmpdir: Any) -> None:
+    # Same input as the "art" project in a `repo dumpjson` output
+    prev_data: Dict[str, mk_repo_file.ProjectInfoDict] = {
+        'art': {
+            'url': 'https://android.googlesource.com/platform/art',
+            'revisionExpr': 'refs/tags/android-15.0.0_r6',
+            'groups': ['pdk'],
+        },
+    }
+    filename = str(tmpdir / 'repo.json')
+    with patch('mk_repo_file.resolve_ref') as resolve_ref, patch('mk_repo_file.checkout_git') as checkout_git:
+        resolve_ref.return_value = RECORDED_PREFETCH_OUTPUT['rev']
+        checkout_git.return_value = RECORDED_PREFETCH_OUTPUT
+        mk_repo_file.make_repo_file('https://example.com/manifest', 'release', prev_data=prev_data,
+                                    callback=lambda data: save(filename, data))
+
+    assert open(filename).read() == GOLDEN_REPO_JSON
+
+
+def test_load_excludes(tmpdir: Any) -> None:
+    excludes_filename = tmpdir / 'excludes.toml'
+    excludes_filename.write('[exclude]\n"prebuilts/jdk/jdk17" = "Replaced by the JDK from nixpkgs"\n')
+    assert mk_repo_file.load_excludes(str(excludes_filename)) == {
+        'prebuilts/jdk/jdk17': 'Replaced by the JDK from nixpkgs',
+    }
+
+    (tmpdir / 'empty.toml').write('')
+    assert mk_repo_file.load_excludes(str(tmpdir / 'empty.toml')) == {}
+
+
+def test_in_groups() -> None:
+    assert mk_repo_file.in_groups([], [], [])
+    assert mk_repo_file.in_groups(['pdk'], ['default'], [])
+    assert not mk_repo_file.in_groups(['notdefault'], ['default'], [])
+    assert mk_repo_file.in_groups(['notdefault', 'tools'], ['default', 'tools'], [])
+    assert not mk_repo_file.in_groups(['pdk', 'darwin'], [], ['darwin', 'mips'])
+    assert not mk_repo_file.in_groups(['pdk', 'darwin'], ['pdk'], ['darwin'])
+
+
+def test_lfs_detection(tmpdir: Any) -> None:
+    checkout = tmpdir.mkdir('checkout')
+    (checkout / '.gitattributes').write('*.apk filter=lfs diff=lfs merge=lfs -text\n')
+    prev_data: Dict[str, mk_repo_file.ProjectInfoDict] = {
+        'vendor/example': {
+            'url': 'https://example.com/vendor_example',
+            'revisionExpr': 'refs/tags/android-15.0.0_r6',
+        },
+    }
+    with patch('mk_repo_file.resolve_ref') as resolve_ref, patch('mk_repo_file.checkout_git') as checkout_git:
+        resolve_ref.return_value = RECORDED_PREFETCH_OUTPUT['rev']
+        checkout_git.return_value = {**RECORDED_PREFETCH_OUTPUT, 'path': str(checkout)}
+        data = mk_repo_file.make_repo_file('https://example.com/manifest', 'release', prev_data=prev_data,
+                                           fetch_lfs=False)
+
+        # Fetched again with LFS once it turns out the project uses it
+        assert [c.args[3] for c in checkout_git.call_args_list] == [False, True]
+        assert data['vendor/example']['fetchLFS']
+
+        # The same revision elsewhere gets the cached hash, which includes the LFS files
+        checkout_git.reset_mock()
+        prev_data = {'vendor/copy': {**prev_data['vendor/example']}}
+        data = mk_repo_file.make_repo_file('https://example.com/manifest', 'release', prev_data=prev_data,
+                                           fetch_lfs=False)
+        checkout_git.assert_not_called()
+        assert data['vendor/copy']['fetchLFS']
+
+    # A hash cached without LFS files isn't used for an --lfs-project
+    with patch('mk_repo_file.resolve_ref') as resolve_ref, patch('mk_repo_file.checkout_git') as checkout_git, \
+            patch.dict(mk_repo_file.revInfo, clear=True):
+        resolve_ref.return_value = RECORDED_PREFETCH_OUTPUT['rev']
+        checkout_git.return_value = RECORDED_PREFETCH_OUTPUT
+        mk_repo_file.make_repo_file('https://example.com/manifest', 'release', fetch_lfs=False,
+                                    prev_data={'vendor/plain': {**prev_data['vendor/copy']}})
+        data = mk_repo_file.make_repo_file('https://example.com/manifest', 'release', fetch_lfs=False,
+                                           prev_data={'vendor/forced': {**prev_data['vendor/copy']}},
+                                           lfs_projects=['vendor/forced'])
+        assert [c.args[3] for c in checkout_git.call_args_list] == [False, True]
+        assert data['vendor/forced']['fetchLFS']
@@ -0,0 +1,392 @@
+# SPDX-FileCopyrightText: 2026 robotnix contributors
+# SPDX-License-Identifier: MIT
+
+import argparse
+import email.message
+import io
+import json
+import os
+import pytest
+import subprocess
+import urllib.error
+
+from typing import Any, Dict, List
+from unittest.mock import patch
+
+import robotnix_common
+
+
+def test_diff_entries() -> None:
+    old = {
+        'a': {'rev': '1111111111111111', 'sha256': 'foo'},
+        'b': {'rev': '2222222222222222', 'sha256': 'bar'},
+        'c': {'rev': '3333333333333333', 'sha256': 'baz'},
+    }
+    new = {
+        'a': {'rev': '1111111111111111', 'sha256': 'foo'},
+        'b': {'rev': '4444444444444444', 'sha256': 'qux'},
+        'c': {'rev': '3333333333333333', 'sha256': 'baz', 'fetchSubmodules': True},
+        'd': {'rev': '5555555555555555', 'sha256': 'quux'},
+    }
+    assert robotnix_common.diff_entries(old, new) == [
+        '~ b: 222222222222 -> 444444444444',
+        '~ c',
+        '+ d',
+    ]
+    assert robotnix_common.diff_entries(new, old)[-1] == '- d'
+    assert robotnix_common.compare_entries(old, new)[0] == {
+        'change': '~', 'key': 'b', 'old_rev': '2222222222222222', 'new_rev': '4444444444444444',
+    }
+
+
+def test_same_entry() -> None:
+    entry = {
+        'url': 'https://github.com/LineageOS/android_device_fairphone_FP4',
+        'rev': '1111111111111111',
+        'sha256': 'foo',
+        'deps': ['kernel/fairphone/sm7225', 'hardware/fairphone'],
+    }
+    # Differences in what nix-prefetch-git reported about its checkout, defaults and ordering don't matter
+    assert robotnix_common.same_entry(entry, {
+        **entry,
+        'url': 'https://github.com/LineageOS/android_device_fairphone_FP4.git',
+        'deps': ['hardware/fairphone', 'kernel/fairphone/sm7225'],
+        'date': '2024-11-30T17:50:05+02:00',
+        'path': '/nix/store/foo-android_device_fairphone_FP4',
+        'fetchSubmodules': False,
+    })
+    assert not robotnix_common.same_entry(entry, {**entry, 'fetchSubmodules': True})
+    assert not robotnix_common.same_entry(entry, {**entry, 'deps': ['hardware/fairphone']})
+    assert robotnix_common.same_entry({'branch': 'lineage-22.1', 'lineage_recovery': False}, {'branch': 'lineage-22.1'})
+
+
+def test_check_up_to_date(tmpdir: Any) -> None:
+    filename = str(tmpdir / 'repo.json')
+    data = {'a': {'rev': 'foo', 'sha256': 'bar'}}
+    robotnix_common.save(filename, data)
+    assert json.load(open(filename)) == data
+
+    assert robotnix_common.check_up_to_date(filename, data)
+    assert not robotnix_common.check_up_to_date(filename, {})
+    assert not robotnix_common.check_up_to_date(str(tmpdir / 'missing.json'), data)
+
+
+def test_staging(tmpdir: Any) -> None:
+    filename = str(tmpdir / 'device-metadata.json')
+    robotnix_common.save(filename, {'FP4': 'old'})
+    os.chmod(filename, 0o644)
+
+    staging_dir = str(tmpdir / 'staging')
+    with patch.object(robotnix_common, 'STAGING_DIR', staging_dir):
+        robotnix_common.save(filename, {'FP4': 'new'})
+        # Staged files are read in preference to the real ones, which stay untouched until committed
+        assert robotnix_common.load(filename) == {'FP4': 'new'}
+        assert json.load(open(filename)) == {'FP4': 'old'}
+        assert robotnix_common.load(str(tmpdir / 'missing.json'), {}) == {}
+    assert robotnix_common.commit_staged(staging_dir) == [filename]
+    assert json.load(open(filename)) == {'FP4': 'new'}
+    assert not os.path.exists(staging_dir)
+    # Nothing else is left behind, and the file keeps its permissions
+    assert os.listdir(tmpdir) == ['device-metadata.json']
+    assert os.stat(filename).st_mode & 0o777 == 0o644
+
+    # An interrupted run leaves the real files alone, and keeps what was staged for resuming
+    with pytest.raises(KeyboardInterrupt), patch.object(robotnix_common, 'STAGING_DIR', ''):
+        with robotnix_common.staged_outputs():
+            robotnix_common.save(filename, {'FP4': 'newer'})
+            staging_dir = robotnix_common.STAGING_DIR
+            raise KeyboardInterrupt()
+    assert json.load(open(filename)) == {'FP4': 'new'}
+    with patch.object(robotnix_common, 'STAGING_DIR', staging_dir):
+        assert robotnix_common.load(filename) == {'FP4': 'newer'}
+        with robotnix_common.staged_outputs():
+            pass
+    robotnix_common.commit_staged(staging_dir)
+    assert json.load(open(filename)) == {'FP4': 'newer'}
+
+
+def test_normalize_url() -> None:
+    canonical = 'https://github.com/LineageOS/android_device_fairphone_FP4'
+    assert robotnix_common.normalize_url(canonical) == canonical
+    assert robotnix_common.normalize_url(f'{canonical}/') == canonical
+    assert robotnix_common.normalize_url(f'{canonical}.git') == canonical
+    assert robotnix_common.normalize_url('HTTPS://GitHub.com/LineageOS/android_device_fairphone_FP4.git/') == canonical
+
+    assert robotnix_common.diff_entries({'a': {'url': canonical}}, {'a': {'url': f'{canonical}.git'}}) == []
+
+
+def test_ls_remote_cache(tmpdir: Any) -> None:
+    url = 'https://github.com/LineageOS/android_device_fairphone_FP4'
+    with patch.object(robotnix_common, 'LS_REMOTE_CACHE_TTL', 3600), \
+            patch.object(robotnix_common, 'LS_REMOTE_CACHE_FILE', str(tmpdir / 'ls-remote.json')), \
+            patch.object(robotnix_common, 'LS_REMOTE_CACHE', None), \
+            patch.object(robotnix_common, 'REMOTE_REFS', {}), \
+            patch('robotnix_common.atexit'), \
+            patch('robotnix_common.check_output_with_retries') as check_output:
+        check_output.return_value = b'0123456789abcdef\trefs/heads/lineage-22.1\n'
+        assert robotnix_common.ls_remote(url) == {'refs/heads/lineage-22.1': '0123456789abcdef'}
+        robotnix_common._save_ls_remote_cache()
+
+        # A later invocation reads the refs from disk instead of querying the remote again
+        robotnix_common.LS_REMOTE_CACHE = None
+        robotnix_common.REMOTE_REFS = {}
+        check_output.side_effect = Exception('Called ls-remote')
+        assert robotnix_common.ls_remote(f'{url}.git') == {'refs/heads/lineage-22.1': '0123456789abcdef'}
+
+        # Until the entries expire
+        robotnix_common.LS_REMOTE_CACHE = None
+        robotnix_common.REMOTE_REFS = {}
+        cached_time = json.load(open(tmpdir / 'ls-remote.json'))[url]['time']
+        with patch('robotnix_common.time.time') as time, pytest.raises(Exception, match='Called ls-remote'):
+            time.return_value = cached_time + robotnix_common.LS_REMOTE_CACHE_TTL
+            robotnix_common.ls_remote(url)
+
+
+def test_ls_remote_patterns() -> None:
+    url = 'https://github.com/LineageOS/android_kernel_fairphone_sm7225'
+    with patch.object(robotnix_common, 'REMOTE_REFS', {}), \
+            patch('robotnix_common.check_output_with_retries') as check_output:
+        check_output.return_value = b'0123456789abcdef\trefs/heads/lineage-22.1\n'
+        assert robotnix_common.ls_remote(url, ['lineage-22.1']) == {'refs/heads/lineage-22.1': '0123456789abcdef'}
+        assert check_output.call_args.args[0] == ['git', 'ls-remote', url, 'lineage-22.1']
+
+        # A filtered listing isn't mistaken for the full one, but a full listing answers patterns it has refs for
+        check_output.return_value = b'0123456789abcdef\trefs/heads/lineage-22.1\nfedcba9876543210\trefs/tags/v1\n'
+        assert len(robotnix_common.ls_remote(url)) == 2
+        assert len(robotnix_common.ls_remote(url, ['v1'])) == 2
+        assert len(robotnix_common.ls_remote(url, ['refs/heads/lineage-*'])) == 2
+        assert check_output.call_count == 2
+
+        # Refs missing from the full listing are asked for, since servers don't list all of them (like
+        # Gerrit's refs/changes/)
+        check_output.return_value = b'0123456789abcdef\trefs/changes/45/12345/2\n'
+        assert robotnix_common.ls_remote(url, ['refs/changes/45/12345/2']) == {
+            'refs/changes/45/12345/2': '0123456789abcdef'}
+        assert check_output.call_args.args[0] == ['git', 'ls-remote', url, 'refs/changes/45/12345/2']
+        assert not robotnix_common.ref_matches('refs/heads/lineage-22.1', 'age-22.1')
+
+
+def test_resolve_ref() -> None:
+    url = 'https://android.googlesource.com/platform/art'
+    tag_object = '1111111111111111111111111111111111111111'
+    commit = '2222222222222222222222222222222222222222'
+    with patch.object(robotnix_common, 'REMOTE_REFS', {}), \
+            patch('robotnix_common.check_output_with_retries') as check_output:
+        # What git ls-remote prints for an annotated tag, when asked for both the tag and its peeled commit
+        check_output.return_value = f'{tag_object}\trefs/tags/v1\n{commit}\trefs/tags/v1^{{}}\n'.encode()
+        assert robotnix_common.resolve_ref(url, 'v1') == commit
+        assert check_output.call_args.args[0] == ['git', 'ls-remote', url, 'v1', 'v1^{}']
+
+        # Refs only listed on request, like Gerrit's change refs
+        check_output.return_value = f'{commit}\trefs/changes/45/12345/2\n'.encode()
+        assert robotnix_common.resolve_ref(url, 'refs/changes/45/12345/2') == commit
+
+        check_output.return_value = b''
+        assert robotnix_common.resolve_ref(url, 'refs/heads/gone') is None
+
+
+def test_offline(tmpdir: Any) -> None:
+    url = 'https://github.com/LineageOS/android_device_fairphone_FP4'
+    with open(tmpdir / 'ls-remote.json', 'w') as f:
+        json.dump({url: {'time': 0, 'refs': {'refs/heads/lineage-22.1': '0123456789abcdef'}}}, f)
+    with patch.object(robotnix_common, 'OFFLINE', True), \
+            patch.object(robotnix_common, 'LS_REMOTE_CACHE_FILE', str(tmpdir / 'ls-remote.json')), \
+            patch.object(robotnix_common, 'LS_REMOTE_CACHE', None), \
+            patch.object(robotnix_common, 'REMOTE_REFS', {}), \
+            patch('robotnix_common.atexit'), \
+            patch('robotnix_common.check_output_with_retries', side_effect=Exception('Called git')):
+        # Expired entries are still used
+        assert robotnix_common.ls_remote(url) == {'refs/heads/lineage-22.1': '0123456789abcdef'}
+        with pytest.raises(robotnix_common.OfflineError):
+            robotnix_common.ls_remote('https://github.com/LineageOS/android_device_oneplus_cheeseburger')
+        with pytest.raises(robotnix_common.OfflineError):
+            robotnix_common.checkout_git(url, 'refs/heads/lineage-22.1')
+
+
+def test_common_options(tmpdir: Any, capsys: Any) -> None:
+    def resolve(*args: str) -> Dict[str, Any]:
+        parser = argparse.ArgumentParser()
+        robotnix_common.add_common_args(parser)
+        return robotnix_common.resolve_common_options(parser.parse_args(args))
+
+    user_config = str(tmpdir / 'user.toml')
+    project_config = str(tmpdir / 'project.toml')
+    with open(user_config, 'w') as f:
+        f.write('retries = 4\nretry-delay = 2\nkeep-tmp = true\n')
+    with open(project_config, 'w') as f:
+        f.write('retries = 5\n')
+    with patch.object(robotnix_common, 'SYSTEM_CONFIG_FILE', str(tmpdir / 'missing.toml')), \
+            patch.object(robotnix_common, 'USER_CONFIG_FILE', user_config), \
+            patch.object(robotnix_common, 'PROJECT_CONFIG_FILE', project_config), \
+            patch.dict(os.environ, {'ROBOTNIX_RETRY_DELAY': '0.5', 'ROBOTNIX_OFFLINE': '1'}):
+        assert resolve() == {
+            'retries': (5, project_config),
+            'retry_delay': (0.5, '$ROBOTNIX_RETRY_DELAY'),
+            'keep_tmp': (True, user_config),
+            'offline': (True, '$ROBOTNIX_OFFLINE'),
+        }
+        # The command line takes precedence over everything
+        assert resolve('--retries', '1', '--retry-delay', '3')['retries'] == (1, 'command line')
+        assert resolve('--retry-delay', '3')['retry_delay'] == (3.0, 'command line')
+        # --config is read instead of the project config file
+        with open(tmpdir / 'ci.toml', 'w') as f:
+            f.write('retries = 9\n')
+        assert resolve('--config', str(tmpdir / 'ci.toml'))['retries'] == (9, str(tmpdir / 'ci.toml'))
+
+        robotnix_common.show_config(resolve(), {})
+    assert 'retries                    5  (' + project_config + ')' in capsys.readouterr().out
+
+    with open(project_config, 'w') as f:
+        f.write('offline = "yes"\n')
+    with patch.object(robotnix_common, 'PROJECT_CONFIG_FILE', project_config), \
+            pytest.raises(ValueError, match='offline .* should be a bool'):
+        resolve()
+
+
+def test_host_limits(tmpdir: Any, capsys: Any) -> None:
+    project_config = str(tmpdir / 'project.toml')
+    with open(project_config, 'w') as f:
+        f.write('[hosts."review.example.org"]\nmin-delay = 2\nmax-connections = 1\n')
+    parser = argparse.ArgumentParser()
+    robotnix_common.add_common_args(parser)
+    args = parser.parse_args([])
+    with patch.object(robotnix_common, 'SYSTEM_CONFIG_FILE', str(tmpdir / 'missing.toml')), \
+            patch.object(robotnix_common, 'USER_CONFIG_FILE', str(tmpdir / 'missing.toml')), \
+            patch.object(robotnix_common, 'PROJECT_CONFIG_FILE', project_config):
+        host_limits = robotnix_common.resolve_host_limits(args)
+        assert host_limits == {'review.example.org': ({'min_delay': 2.0, 'max_connections': 1}, project_config)}
+        robotnix_common.show_config(robotnix_common.resolve_common_options(args), host_limits)
+    assert 'host review.example.org    min-delay=2.0 max-connections=1' in capsys.readouterr().out
+
+    sleeps: List[float] = []
+    with patch.object(robotnix_common, 'HOST_LIMITS', {}), patch.object(robotnix_common, 'HOST_STATE', {}), \
+            patch('robotnix_common.time.monotonic', return_value=100.0), \
+            patch('robotnix_common.time.sleep', side_effect=sleeps.append):
+        robotnix_common.set_host_limits({host: limits for host, (limits, _) in host_limits.items()})
+        for url in ['https://review.example.org/a', 'https://github.com/b', 'https://review.example.org/c']:
+            with robotnix_common.host_limited(url):
+                pass
+    # Hosts without limits aren't held up at all
+    assert sleeps == [0.0, 2.0]
+
+
+def test_check_output_with_retries() -> None:
+    # Every call runs the next of these shell scripts instead of the real command
+    scripts: List[str] = []
+    popen = subprocess.Popen
+
+    def fake_popen(args: List[str], **kwargs: Any) -> subprocess.Popen[bytes]:
+        return popen(['sh', '-c', scripts.pop(0)], **kwargs)
+
+    def failure(stderr: str) -> str:
+        return f"echo '{stderr}' >&2; exit 128"
+
+    success = 'echo -n output'
+    with patch('robotnix_common.subprocess.Popen', side_effect=fake_popen) as run, \
+            patch('robotnix_common.time.sleep') as sleep:
+        scripts[:] = [failure('fatal: unable to access: Connection timed out'),
+                      failure('fatal: unable to access: The requested URL returned error: 502'), success]
+        assert robotnix_common.check_output_with_retries(['git', 'ls-remote', 'url']) == b'output'
+        delay = robotnix_common.RETRY_DELAY
+        assert [c.args[0] for c in sleep.call_args_list] == [delay, 2 * delay]
+        # git never waits for credentials
+        assert run.call_args.kwargs['env']['GIT_TERMINAL_PROMPT'] == '0'
+
+        # Missing repos fail right away
+        run.reset_mock()
+        scripts[:] = [failure('remote: Repository not found.'), success]
+        with pytest.raises(subprocess.CalledProcessError):
+            robotnix_common.check_output_with_retries(['git', 'ls-remote', 'url'])
+        assert run.call_count == 1
+        scripts[:] = [failure('fatal: unable to access: The requested URL returned error: 403'), success]
+        with pytest.raises(subprocess.CalledProcessError):
+            robotnix_common.check_output_with_retries(['git', 'ls-remote', 'url'])
+
+        # And transient errors give up eventually
+        run.reset_mock()
+        scripts[:] = [failure('fatal: unable to access: Connection timed out')] * (robotnix_common.RETRIES + 1)
+        with pytest.raises(subprocess.CalledProcessError):
+            robotnix_common.check_output_with_retries(['git', 'ls-remote', 'url'])
+        assert run.call_count == robotnix_common.RETRIES + 1
+
+        # Rate limiting pauses for longer
+        sleep.reset_mock()
+        scripts[:] = [failure('fatal: unable to access: The requested URL returned error: 429'), success]
+        assert robotnix_common.check_output_with_retries(['git', 'ls-remote', 'url']) == b'output'
+        assert sleep.call_args.args[0] == robotnix_common.RATE_LIMIT_DELAY
+
+        # But progress output which happens to contain 429 isn't rate limiting
+        sleep.reset_mock()
+        scripts[:] = [failure('Receiving objects: 429/1000\nfatal: early EOF'), success]
+        assert robotnix_common.check_output_with_retries(['git', 'ls-remote', 'url']) == b'output'
+        assert sleep.call_args.args[0] == robotnix_common.RETRY_DELAY
+
+        # Only the end of stderr is kept for classifying the error, however long it gets
+        scripts[:] = [f"head -c 100000 /dev/zero | tr '\\0' x >&2; {failure('remote: Repository not found.')}"]
+        with pytest.raises(subprocess.CalledProcessError) as error:
+            robotnix_common.check_output_with_retries(['git', 'ls-remote', 'url'])
+        assert len(error.value.stderr) == robotnix_common.STDERR_TAIL
+        assert error.value.stderr.endswith(b'remote: Repository not found.\n')
+
+
+def test_github_api_rate_limit() -> None:
+    def forbidden(headers: Dict[str, str]) -> urllib.error.HTTPError:
+        message = email.message.Message()
+        for name, value in headers.items():
+            message[name] = value
+        return urllib.error.HTTPError('https://api.github.com/', 403, 'Forbidden', message, None)
+
+    response = io.BytesIO(b'{"name": "hudson"}')
+    with patch('robotnix_common.urllib.request.urlopen', side_effect=[forbidden({'Retry-After': '30'}), response]), \
+            patch('robotnix_common.time.sleep') as sleep:
+        assert robotnix_common.github_api('repos/LineageOS/hudson') == {'name': 'hudson'}
+        assert sleep.call_args.args[0] == 30
+
+    # 403s which aren't about rate limits are raised right away
+    with patch('robotnix_common.urllib.request.urlopen', side_effect=[forbidden({})]), \
+            pytest.raises(urllib.error.HTTPError):
+        robotnix_common.github_api('repos/LineageOS/private')
+
+
+def test_checkout_git_removes_tmpdir() -> None:
+    tmpdirs = []
+
+    def check_output_with_retries(args: List[str], env: Dict[str, str]) -> bytes:
+        tmpdirs.append(env['TMPDIR'])
+        assert os.path.isdir(env['TMPDIR'])
+        raise subprocess.CalledProcessError(1, args)
+
+    with patch('robotnix_common.check_output_with_retries', side_effect=check_output_with_retries), \
+            pytest.raises(subprocess.CalledProcessError):
+        robotnix_common.checkout_git('https://example.com/repo', 'refs/heads/main')
+    assert len(tmpdirs) == 1
+    assert not os.path.exists(tmpdirs[0])
+
+
+def test_checkout_cache(tmpdir: Any) -> None:
+    url = 'https://github.com/LineageOS/hudson'
+    rev = '0123456789abcdef0123456789abcdef01234567'
+    store_path = tmpdir.mkdir('store-hudson')
+    git_info = {'url': url, 'rev': rev, 'path': str(store_path), 'sha256': 'hash', 'date': 'date'}
+    with patch.object(robotnix_common, 'CHECKOUT_CACHE_ENABLED', True), \
+            patch.object(robotnix_common, 'CHECKOUT_CACHE_FILE', str(tmpdir / 'checkouts.json')), \
+            patch.object(robotnix_common, 'CHECKOUT_CACHE', None), \
+            patch('robotnix_common.atexit'), \
+            patch('robotnix_common.ls_remote', return_value={'refs/heads/main': rev}), \
+            patch('robotnix_common.check_output_with_retries') as check_output:
+        check_output.return_value = json.dumps(git_info).encode()
+        assert robotnix_common.checkout_git(url, 'refs/heads/main') == git_info
+        robotnix_common._save_checkout_cache()
+
+        # A later invocation reuses the checkout, whether it asks for the ref or the commit
+        robotnix_common.CHECKOUT_CACHE = None
+        check_output.side_effect = Exception('Called nix-prefetch-git')
+        assert robotnix_common.checkout_git(url, 'refs/heads/main') == git_info
+        assert robotnix_common.checkout_git(f'{url}.git', rev) == git_info
+
+        # But not with different flags, or once the store path is gone
+        with pytest.raises(Exception, match='Called nix-prefetch-git'):
+            robotnix_common.checkout_git(url, rev, fetch_submodules=True)
+        store_path.remove()
+        with pytest.raises(Exception, match='Called nix-prefetch-git'):
+            robotnix_common.checkout_git(url, rev)
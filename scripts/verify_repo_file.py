@@ -0,0 +1,71 @@
+#!/usr/bin/env python3
+# SPDX-FileCopyrightText: 2026 robotnix contributors
+# SPDX-License-Identifier: MIT
+
+from typing import Any, Dict, List, Optional, Tuple
+
+import argparse
+import json
+import multiprocessing
+import multiprocessing.pool
+import re
+import subprocess
+import sys
+
+from robotnix_common import resolve_ref, is_permanent_error
+
+
+def verify(data: Dict[str, Any], default_ref: Optional[str] = None,
+           jobs: int = 1) -> Tuple[List[str], List[str], List[str]]:
+    """Re-resolve the ref of every entry without fetching anything.
+    Returns (stale entries, entries gone upstream, entries which couldn't be checked)"""
+    def verify_entry(item: Tuple[str, Any]) -> Tuple[Optional[str], Optional[str], Optional[str]]:
+        relpath, p = item
+        # Device and vendor dirs only record their ref (or the one given with --override) if it isn't
+        # the branch given with --ref. Those pinned to a commit have nothing to compare against
+        ref = p.get('overrideRef') or p.get('ref') or p.get('revisionExpr', default_ref)
+        if ref is None or re.fullmatch('[0-9a-f]{40}', ref):
+            return None, None, None
+        try:
+            rev = resolve_ref(p['url'], ref)
+        except subprocess.CalledProcessError as e:
+            if is_permanent_error(e):
+                return None, f"{relpath}: {p['url']} is gone", None
+            return None, None, f"{relpath}: {p['url']} could not be reached"
+        if rev is None:
+            return None, f"{relpath}: {ref} no longer exists in {p['url']}", None
+        if rev != p['rev']:
+            return f"{relpath}: {ref} is at {rev[:12]}, pinned {p['rev'][:12]}", None, None
+        return None, None, None
+
+    with multiprocessing.pool.ThreadPool(jobs) as pool:
+        results = pool.map(verify_entry, sorted(data.items()))
+    stale = [s for s, _, _ in results if s is not None]
+    gone = [g for _, g, _ in results if g is not None]
+    unreachable = [u for _, _, u in results if u is not None]
+    return stale, gone, unreachable
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser(
+        description="check whether the pins in a repo json file still match upstream, without fetching anything")
+    parser.add_argument('--ref', help="ref to check entries against which don't record their own "
+                        "(e.g. refs/heads/lineage-21 for LineageOS device-dirs.json)")
+    parser.add_argument('--jobs', '-j', default=multiprocessing.cpu_count(), type=int, help="number of concurrent jobs")
+    parser.add_argument('file', help="repo json file to check")
+    args = parser.parse_args()
+
+    stale, gone, unreachable = verify(json.load(open(args.file)), args.ref, args.jobs)
+    for line in stale:
+        print(f'STALE: {line}')
+    for line in gone:
+        print(f'GONE: {line}')
+    for line in unreachable:
+        print(f'UNREACHABLE: {line}')
+    print(f'{len(stale)} stale, {len(gone)} gone upstream, {len(unreachable)} could not be checked')
+    if len(stale) > 0 or len(gone) > 0 or len(unreachable) > 0:
+        sys.exit(1)
+
+
+if __name__ == "__main__":
+    main()